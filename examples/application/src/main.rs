@@ -22,9 +22,10 @@ async fn main() {
         dbname: "postgres".into(),
     };
     let replication_config = outbox::ReplicationConfig {
-        table: "events".into(),
+        tables: vec!["events".into()],
         publication: "events_pub".into(),
         replication_slot: "events_slot".into(),
+        ..Default::default()
     };
     let amqp_connection =
         Connection::connect("amqp://127.0.0.1:5672", ConnectionProperties::default())
@@ -2,6 +2,6 @@ mod model;
 mod publisher;
 
 pub use model::Publish;
-pub use publisher::AmqpPublisher;
+pub use publisher::{AmqpPublisher, COMMIT_TIME_HEADER, LSN_HEADER};
 
 pub use lapin::BasicProperties;
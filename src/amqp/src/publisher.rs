@@ -1,7 +1,18 @@
-use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use cdc_framework::Metadata;
+use chrono::SecondsFormat;
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    types::AMQPValue,
+    BasicProperties,
+};
 
 use crate::Publish;
 
+/// Header holding the LSN of the change a message was published for.
+pub const LSN_HEADER: &str = "x-source-lsn";
+/// Header holding the commit time of the change a message was published for, in RFC 3339.
+pub const COMMIT_TIME_HEADER: &str = "x-commit-time";
+
 pub struct AmqpPublisher<M> {
     channel: lapin::Channel,
     _config: PublisherConfiguraton,
@@ -31,6 +42,14 @@ impl<M> AmqpPublisher<M> {
     }
 
     pub async fn publish(&self, m: &impl Publish) -> anyhow::Result<()> {
+        self.publish_with_properties(m, m.properties()).await
+    }
+
+    async fn publish_with_properties(
+        &self,
+        m: &impl Publish,
+        properties: BasicProperties,
+    ) -> anyhow::Result<()> {
         let confirmation = self
             .channel
             .basic_publish(
@@ -38,12 +57,17 @@ impl<M> AmqpPublisher<M> {
                 m.routing_key(),
                 BasicPublishOptions::default(),
                 m.payload().as_ref(),
-                m.properties(),
+                properties,
             )
             .await?;
 
         let confirmation = confirmation.await?;
         anyhow::ensure!(confirmation.is_ack());
+        tracing::debug!(
+            exchange = m.exchange(),
+            routing_key = m.routing_key(),
+            "published"
+        );
 
         Ok(())
     }
@@ -53,12 +77,40 @@ impl<M> cdc_framework::EventHandler<outbox::model::EventRecord> for AmqpPublishe
 where
     M: outbox::model::Message + Publish + Send + Sync,
 {
-    async fn handle(&self, msg: outbox::model::EventRecord) -> anyhow::Result<()> {
-        let msg = M::from_record(msg)?;
-        self.publish(&msg).await
+    async fn handle(
+        &self,
+        msg: cdc_framework::Envelope<outbox::model::EventRecord>,
+    ) -> anyhow::Result<()> {
+        // Only new or updated records are published
+        let Some(record) = msg.change.into_after() else {
+            return Ok(());
+        };
+        let m = M::from_record(record)?;
+        let properties = with_metadata_headers(m.properties(), &msg.metadata);
+        self.publish_with_properties(&m, properties).await
     }
 }
 
+/// Stamps the source position and commit time of a change,
+/// e.g. for consumers to deduplicate or measure latency.
+fn with_metadata_headers(properties: BasicProperties, metadata: &Metadata) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(
+        LSN_HEADER.into(),
+        AMQPValue::LongString(metadata.lsn.to_string().into()),
+    );
+    headers.insert(
+        COMMIT_TIME_HEADER.into(),
+        AMQPValue::LongString(
+            metadata
+                .commit_time
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        ),
+    );
+    properties.with_headers(headers)
+}
+
 #[derive(Debug, Clone, Default)]
 #[non_exhaustive]
 pub struct PublisherConfiguraton {
@@ -1,4 +1,6 @@
-#[derive(Debug)]
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
 pub struct DbConfig {
     pub host: String,
     pub port: u16,
@@ -20,9 +22,253 @@ impl DbConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReplicationConfig {
-    pub table: String,
+    /// Tables replicated by the publication.
+    pub tables: Vec<PublicationTable>,
+    /// Schemas whose tables are all replicated by the publication, including
+    /// tables created later on (requires Postgres 15+).
+    pub schemas: Vec<String>,
     pub publication: String,
     pub replication_slot: String,
+    pub publish: PublishOperations,
+    /// Stream large in-progress transactions instead of having Postgres
+    /// decode them in full before sending (requires Postgres 14+).
+    ///
+    /// Their changes are still only handled once committed, being buffered
+    /// until then, see `streaming_memory`.
+    pub streaming: bool,
+    /// Bytes of streamed transactions kept in memory until they commit,
+    /// beyond which their changes are spilled to temporary files.
+    pub streaming_memory: usize,
+    /// Receive column values in binary rather than text format (requires Postgres 14+).
+    ///
+    /// All replicated column types must have a binary representation.
+    pub binary: bool,
+    /// When creating the replication slot, first hand the existing rows of the
+    /// published tables to the handler as [`ChangeEvent::Snapshot`]s, as of the
+    /// point the slot starts streaming from.
+    ///
+    /// [`ChangeEvent::Snapshot`]: crate::ChangeEvent::Snapshot
+    pub snapshot: bool,
+    /// Enables incremental snapshots, see [`SnapshotHandle`], using this table
+    /// for watermarks. It is created and added to the publication, and may be
+    /// shared by several replication slots.
+    ///
+    /// [`SnapshotHandle`]: crate::SnapshotHandle
+    pub watermark_table: Option<String>,
+    /// How to fill in the TOASTed values an UPDATE did not change.
+    ///
+    /// `None` leaves it to the subscriber, which keeps them as is, see
+    /// [`UnchangedToast::Keep`], unless it needs the full rows.
+    pub unchanged_toast: Option<UnchangedToast>,
+    /// Which changes to stream, depending on their replication origin.
+    pub origin: OriginFilter,
+    /// Create the replication slot as a failover slot, which Postgres
+    /// synchronizes to standbys configured with `sync_replication_slots`,
+    /// or enable failover on the existing slot (requires Postgres 17+).
+    ///
+    /// On a promoted standby, subscribers resume from the synchronized slot.
+    /// They fail with [`Error::SlotNotSynchronized`] rather than creating a
+    /// new slot, which would miss changes, if it is missing although the
+    /// publication already exists, as they are created together.
+    ///
+    /// [`Error::SlotNotSynchronized`]: crate::Error::SlotNotSynchronized
+    pub failover: bool,
+    /// Only lets one subscriber stream from the replication slot at a time,
+    /// others waiting to take over once its connection closes, e.g. when
+    /// running several replicas for availability.
+    ///
+    /// Creating a [`Subscriber`] waits until it takes the lead, which lasts
+    /// until its replication connection closes, as the leader is an advisory
+    /// lock held by the connection.
+    ///
+    /// [`Subscriber`]: crate::Subscriber
+    pub leader_election: bool,
+    /// How often to report the processed position to Postgres when idle,
+    /// should be well below `wal_sender_timeout`.
+    pub status_interval: Duration,
+    pub dispatch: Dispatch,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            tables: vec![],
+            schemas: vec![],
+            publication: String::new(),
+            replication_slot: String::new(),
+            publish: PublishOperations::default(),
+            streaming: false,
+            streaming_memory: 64 * 1024 * 1024,
+            binary: false,
+            snapshot: false,
+            watermark_table: None,
+            unchanged_toast: None,
+            origin: OriginFilter::default(),
+            failover: false,
+            leader_election: false,
+            status_interval: Duration::from_secs(10),
+            dispatch: Dispatch::default(),
+        }
+    }
+}
+
+/// A table replicated by the publication, optionally restricted to some
+/// of its columns and rows (requires Postgres 15+).
+#[derive(Debug, Clone)]
+pub struct PublicationTable {
+    pub name: String,
+    /// Only replicate these columns, which must include the replica identity.
+    pub columns: Option<Vec<String>>,
+    /// Only replicate rows matching this SQL expression, e.g. `tenant_id = 42`.
+    ///
+    /// For UPDATEs and DELETEs to be replicated, it may only reference
+    /// columns of the replica identity.
+    pub row_filter: Option<String>,
+}
+
+impl PublicationTable {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            columns: None,
+            row_filter: None,
+        }
+    }
+
+    pub fn columns(mut self, columns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.columns = Some(columns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn row_filter(mut self, row_filter: impl Into<String>) -> Self {
+        self.row_filter = Some(row_filter.into());
+        self
+    }
+}
+
+impl From<String> for PublicationTable {
+    fn from(name: String) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<&str> for PublicationTable {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
+/// e.g. `"public"."events" ("id", "data") WHERE (event_type = 'created')`
+impl std::fmt::Display for PublicationTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", super::quote_table(&self.name))?;
+        if let Some(columns) = &self.columns {
+            let columns = columns
+                .iter()
+                .map(|column| format!(r#""{column}""#))
+                .collect::<Vec<_>>();
+            write!(f, " ({})", columns.join(", "))?;
+        }
+        if let Some(row_filter) = &self.row_filter {
+            write!(f, " WHERE ({row_filter})")?;
+        }
+        Ok(())
+    }
+}
+
+/// How the changes of a transaction are handed to the event handler.
+///
+/// In any case, all changes of a transaction are handled before it is ACKed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dispatch {
+    /// All changes are handled concurrently.
+    #[default]
+    Concurrent,
+    /// Changes sharing an [`Entity::partition_key`](super::Entity::partition_key)
+    /// are handled one after the other, in commit order. Different keys are
+    /// still handled concurrently, TRUNCATEs wait for all previous changes.
+    Partitioned,
+}
+
+/// Large values are stored out of line ("TOASTed"), and `pgoutput` does not
+/// send them in the new row of an UPDATE which did not change them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnchangedToast {
+    /// Leave them as [`Value::UnchangedToast`](super::Value::UnchangedToast).
+    #[default]
+    Keep,
+    /// Copy them from the old row, which is only sent for tables with
+    /// `REPLICA IDENTITY FULL`, otherwise leave them as they are.
+    FromOldRow,
+    /// Copy them from the old row if sent, otherwise look up the row by its
+    /// replica identity, see [`Subscriber::with_lookup_client`]. The values
+    /// looked up may be more recent than the UPDATE.
+    ///
+    /// [`Subscriber::with_lookup_client`]: crate::Subscriber::with_lookup_client
+    Lookup,
+}
+
+/// Filters changes by replication origin, see [`TransactionInfo::origin`].
+///
+/// [`TransactionInfo::origin`]: crate::TransactionInfo::origin
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OriginFilter {
+    /// All changes.
+    #[default]
+    Any,
+    /// Only changes without replication origin, skipping those applied by a
+    /// sink which set one up, e.g. to sync tables both ways without loops
+    /// (requires Postgres 16+).
+    Local,
+}
+
+/// Operations replicated by the publication.
+///
+/// Defaults to INSERTs and UPDATEs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishOperations {
+    pub insert: bool,
+    pub update: bool,
+    pub delete: bool,
+    pub truncate: bool,
+}
+
+impl PublishOperations {
+    pub fn all() -> Self {
+        Self {
+            insert: true,
+            update: true,
+            delete: true,
+            truncate: true,
+        }
+    }
+}
+
+impl Default for PublishOperations {
+    fn default() -> Self {
+        Self {
+            insert: true,
+            update: true,
+            delete: false,
+            truncate: false,
+        }
+    }
+}
+
+impl std::fmt::Display for PublishOperations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operations = [
+            (self.insert, "insert"),
+            (self.update, "update"),
+            (self.delete, "delete"),
+            (self.truncate, "truncate"),
+        ];
+        let operations = operations
+            .into_iter()
+            .filter_map(|(enabled, op)| enabled.then_some(op))
+            .collect::<Vec<_>>();
+        write!(f, "{}", operations.join(", "))
+    }
 }
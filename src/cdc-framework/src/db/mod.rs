@@ -1,13 +1,31 @@
 use std::ops::{Deref, DerefMut};
 
-use tokio_postgres::NoTls;
+use anyhow::Context;
+use tokio_postgres::{NoTls, SimpleQueryMessage, SimpleQueryRow};
+
+use crate::Error;
 
 mod config;
 mod model;
+mod origin;
+mod relation;
 mod setup;
+mod slot;
+mod snapshot;
+mod toast;
+mod value;
 
-pub use config::{DbConfig, ReplicationConfig};
+pub use config::{
+    DbConfig, Dispatch, OriginFilter, PublicationTable, PublishOperations, ReplicationConfig,
+    UnchangedToast,
+};
 pub use model::Entity;
+pub(crate) use relation::copy_tuple_data;
+pub use relation::{Column, Relation, Row};
+pub(crate) use setup::check_missing_slot;
+pub use slot::{ReplicationSlot, WalStatus};
+pub(crate) use snapshot::SnapshotTable;
+pub use value::{Format, FromValue, Toast, Value};
 
 pub struct DbClient<const REPLICATION: bool = false> {
     pub dbname: String,
@@ -15,11 +33,12 @@ pub struct DbClient<const REPLICATION: bool = false> {
 }
 
 impl<const REPLICATION: bool> DbClient<REPLICATION> {
-    pub async fn new(config: &DbConfig) -> anyhow::Result<Self> {
+    pub async fn new(config: &DbConfig) -> crate::Result<Self> {
         let (client, connection) =
             tokio_postgres::connect(&config.connection_string(REPLICATION), NoTls)
                 .await
-                .unwrap();
+                .context("could not connect to database")
+                .map_err(Error::Connection)?;
         tokio::spawn(connection);
 
         Ok(Self {
@@ -42,3 +61,18 @@ impl<const REPLICATION: bool> DerefMut for DbClient<REPLICATION> {
         &mut self.client
     }
 }
+
+/// Quotes a table name, optionally qualified by its schema.
+pub(crate) fn quote_table(table: &str) -> String {
+    match table.split_once('.') {
+        Some((schema, table)) => format!(r#""{schema}"."{table}""#),
+        None => format!(r#""{table}""#),
+    }
+}
+
+fn rows(result: Vec<SimpleQueryMessage>) -> impl Iterator<Item = SimpleQueryRow> {
+    result.into_iter().filter_map(|msg| match msg {
+        SimpleQueryMessage::Row(row) => Some(row),
+        _ => None,
+    })
+}
@@ -1,9 +1,17 @@
-use postgres_replication::protocol::Tuple;
+use std::hash::Hash;
+
+use super::Row;
 
 pub trait Entity: Send + 'static {
     const TABLE: &'static str;
 
-    fn from_tuple(tuple: &Tuple) -> anyhow::Result<Self>
+    fn from_row(row: &Row<'_>) -> anyhow::Result<Self>
     where
         Self: Sized;
+
+    /// Changes of entities sharing a key are handled in order when using
+    /// [`Dispatch::Partitioned`](super::Dispatch::Partitioned).
+    fn partition_key(&self) -> Option<impl Hash> {
+        None::<()>
+    }
 }
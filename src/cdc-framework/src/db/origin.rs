@@ -0,0 +1,34 @@
+use crate::Error;
+
+impl<const REPLICATION: bool> super::DbClient<REPLICATION> {
+    /// Tags the changes made by this connection with a replication origin,
+    /// created if missing, until [`DbClient::reset_replication_origin`].
+    ///
+    /// Used by a sink applying changes, so that a subscriber with
+    /// [`OriginFilter::Local`] does not stream them back.
+    ///
+    /// [`DbClient::reset_replication_origin`]: super::DbClient::reset_replication_origin
+    /// [`OriginFilter::Local`]: super::OriginFilter::Local
+    pub async fn setup_replication_origin(&self, origin: &str) -> crate::Result<()> {
+        self.simple_query(&format!(
+            r#"
+            SELECT pg_replication_origin_create('{origin}')
+            WHERE NOT EXISTS (
+                SELECT 1 FROM pg_replication_origin WHERE roname = '{origin}'
+            );
+            SELECT pg_replication_origin_session_setup('{origin}');
+            "#
+        ))
+        .await
+        .map_err(|e| Error::Setup(e.into()))?;
+        Ok(())
+    }
+
+    /// Changes made by this connection are no longer tagged with an origin.
+    pub async fn reset_replication_origin(&self) -> crate::Result<()> {
+        self.simple_query("SELECT pg_replication_origin_session_reset();")
+            .await
+            .map_err(|e| Error::Setup(e.into()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::borrow::Cow;
+
+use anyhow::Context;
+use postgres_replication::protocol::{RelationBody, Tuple, TupleData};
+use tokio_postgres::types::{Oid, Type};
+
+use super::value::{Format, FromValue, Value};
+
+/// Table metadata sent by `pgoutput` before the first change to a table,
+/// and again whenever its schema changes.
+#[derive(Debug, Clone)]
+pub struct Relation {
+    id: Oid,
+    namespace: String,
+    name: String,
+    columns: Vec<Column>,
+}
+
+impl Relation {
+    pub(crate) fn new(id: Oid, namespace: String, name: String, columns: Vec<Column>) -> Self {
+        Self {
+            id,
+            namespace,
+            name,
+            columns,
+        }
+    }
+
+    pub fn id(&self) -> Oid {
+        self.id
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    fn position(&self, column: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == column)
+    }
+}
+
+impl TryFrom<&RelationBody> for Relation {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &RelationBody) -> Result<Self, Self::Error> {
+        let columns = value
+            .columns()
+            .iter()
+            .map(|column| -> anyhow::Result<_> {
+                Ok(Column {
+                    name: column.name()?.to_string(),
+                    type_oid: column.type_id() as Oid,
+                    is_key: column.flags() & 1 == 1,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            id: value.rel_id(),
+            namespace: value.namespace()?.to_string(),
+            name: value.name()?.to_string(),
+            columns,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    name: String,
+    type_oid: Oid,
+    is_key: bool,
+}
+
+impl Column {
+    pub(crate) fn new(name: String, type_oid: Oid, is_key: bool) -> Self {
+        Self {
+            name,
+            type_oid,
+            is_key,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn type_oid(&self) -> Oid {
+        self.type_oid
+    }
+
+    /// Whether the column is part of the table's replica identity.
+    pub fn is_key(&self) -> bool {
+        self.is_key
+    }
+}
+
+/// A decoded tuple together with the relation it belongs to,
+/// allowing columns to be looked up by name rather than by position.
+#[derive(Debug, Clone, Copy)]
+pub struct Row<'a> {
+    relation: &'a Relation,
+    data: &'a [TupleData],
+    format: Format,
+}
+
+impl<'a> Row<'a> {
+    pub fn new(relation: &'a Relation, tuple: &'a Tuple, format: Format) -> anyhow::Result<Self> {
+        Self::from_data(relation, tuple.tuple_data(), format)
+    }
+
+    pub(crate) fn from_data(
+        relation: &'a Relation,
+        data: &'a [TupleData],
+        format: Format,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            data.len() == relation.columns.len(),
+            "tuple has {} columns, relation {} has {}",
+            data.len(),
+            relation.name,
+            relation.columns.len(),
+        );
+        Ok(Self {
+            relation,
+            data,
+            format,
+        })
+    }
+
+    pub fn relation(&self) -> &'a Relation {
+        self.relation
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Decodes a column according to its type.
+    pub fn get<V: FromValue>(&self, column: &str) -> anyhow::Result<V> {
+        V::from_value(self.value(column)?).with_context(|| format!("column {column}"))
+    }
+
+    pub fn value(&self, column: &str) -> anyhow::Result<Value> {
+        let (col, data) = self.raw(column)?;
+        match data {
+            TupleData::Null => Ok(Value::Null),
+            TupleData::Text(x) => {
+                let ty = Type::from_oid(col.type_oid).with_context(|| {
+                    format!("column {column}: unknown type OID {}", col.type_oid)
+                })?;
+                Value::decode(&ty, self.format, x).with_context(|| format!("column {column}"))
+            }
+            TupleData::UnchangedToast => Ok(Value::UnchangedToast),
+        }
+    }
+
+    /// The column and its undecoded value.
+    pub fn raw(&self, column: &str) -> anyhow::Result<(&'a Column, &'a TupleData)> {
+        let i = self
+            .relation
+            .position(column)
+            .with_context(|| format!("missing column {column}"))?;
+        Ok((&self.relation.columns[i], &self.data[i]))
+    }
+
+    /// Copies the undecoded values, e.g. to decode them later on.
+    pub(crate) fn copy_data(&self) -> Vec<TupleData> {
+        self.data.iter().map(copy_tuple_data).collect()
+    }
+
+    /// Returns the text representation of a non-null column,
+    /// checking that the column has the expected type.
+    ///
+    /// Useful for types not supported by [`Value`], only available for [`Format::Text`].
+    pub fn text(&self, column: &str, ty: &Type) -> anyhow::Result<Cow<'a, str>> {
+        anyhow::ensure!(self.format == Format::Text, "row is not in text format");
+        let (col, data) = self.raw(column)?;
+        anyhow::ensure!(
+            col.type_oid == ty.oid(),
+            "column {column}: expected type {ty}, got OID {}",
+            col.type_oid,
+        );
+        match data {
+            TupleData::Text(x) => Ok(String::from_utf8_lossy(x)),
+            TupleData::Null => anyhow::bail!("column {column}: unexpected null"),
+            TupleData::UnchangedToast => anyhow::bail!("column {column}: unchanged TOAST value"),
+        }
+    }
+}
+
+pub(crate) fn copy_tuple_data(data: &TupleData) -> TupleData {
+    match data {
+        TupleData::Null => TupleData::Null,
+        TupleData::UnchangedToast => TupleData::UnchangedToast,
+        TupleData::Text(x) => TupleData::Text(x.clone()),
+    }
+}
@@ -1,91 +1,356 @@
-use super::config::ReplicationConfig;
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+
+use super::{
+    config::{PublicationTable, PublishOperations, ReplicationConfig},
+    quote_table, rows,
+};
+use crate::Error;
 
 impl<const REPLICATION: bool> super::DbClient<REPLICATION> {
-    pub async fn setup(&self, config: &ReplicationConfig) -> anyhow::Result<()> {
-        // Table has to exist
-        anyhow::ensure!(self.table_exists(&config.table).await?);
+    /// Sets up the publication and the replication slot.
+    ///
+    /// With [`ReplicationConfig::failover`], fails with
+    /// [`Error::SlotNotSynchronized`] rather than creating the slot
+    /// for an existing publication.
+    pub async fn setup(&self, config: &ReplicationConfig) -> crate::Result<()> {
+        let publication_existed = self
+            .publication_exists(&config.publication)
+            .await
+            .map_err(Error::Setup)?;
+        self.setup_publication(config).await?;
+
+        if !self
+            .replication_slot_exists(&config.replication_slot)
+            .await
+            .map_err(Error::Setup)?
+        {
+            check_missing_slot(config, publication_existed)?;
+            self.create_replication_slot(&config.replication_slot, config.failover)
+                .await
+                .map_err(Error::Setup)?;
+        }
+        Ok(())
+    }
+
+    pub async fn setup_publication(&self, config: &ReplicationConfig) -> crate::Result<()> {
+        self.ensure_publication(config).await.map_err(Error::Setup)
+    }
 
-        // Setup publication if not exists
-        if !self.publication_exists(&config.publication).await? {
+    async fn ensure_publication(&self, config: &ReplicationConfig) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !config.tables.is_empty() || !config.schemas.is_empty(),
+            "no tables or schemas to replicate"
+        );
+
+        if let Some(watermark_table) = &config.watermark_table {
             self.simple_query(&format!(
                 r#"
-                CREATE PUBLICATION {publication}
-                FOR TABLE "{table}"
-                WITH (publish = 'insert, update');
+                CREATE TABLE IF NOT EXISTS {watermark_table} (
+                    slot TEXT PRIMARY KEY,
+                    watermark UUID NOT NULL
+                );
                 "#,
-                table = config.table,
-                publication = config.publication,
+                watermark_table = quote_table(watermark_table),
             ))
             .await?;
         }
+        // Tables have to exist
+        let tables = self.configured_tables(config).await?;
 
-        // Setup replication slot if not exists
-        if !self
-            .replication_slot_exists(&config.replication_slot)
-            .await?
-        {
+        // e.g. `TABLE "a", "s"."b" ("id") WHERE (id > 0), TABLES IN SCHEMA "c"`
+        let mut objects = vec![];
+        let table_idents = config
+            .tables
+            .iter()
+            .map(ToString::to_string)
+            .chain(config.watermark_table.as_deref().map(quote_table))
+            .collect::<Vec<_>>();
+        if !table_idents.is_empty() {
+            objects.push(format!("TABLE {}", table_idents.join(", ")));
+        }
+        if !config.schemas.is_empty() {
+            let schemas = config
+                .schemas
+                .iter()
+                .map(|schema| format!(r#""{schema}""#))
+                .collect::<Vec<_>>();
+            objects.push(format!("TABLES IN SCHEMA {}", schemas.join(", ")));
+        }
+        let objects = objects.join(", ");
+        let publication = &config.publication;
+
+        // Setup publication if not exists, otherwise only alter it where it
+        // differs from the configuration, which takes locks on the tables
+        let Some(existing) = self.publication(publication).await? else {
             self.simple_query(&format!(
                 r#"
-                CREATE_REPLICATION_SLOT "{slot}"
-                LOGICAL "pgoutput" NOEXPORT_SNAPSHOT;
+                CREATE PUBLICATION {publication}
+                FOR {objects}
+                WITH (publish = '{publish}');
                 "#,
-                slot = config.replication_slot,
+                publish = config.publish,
             ))
             .await?;
+            tracing::info!(publication, "created publication");
+            return Ok(());
+        };
+
+        if existing.all_tables {
+            tracing::debug!(
+                publication,
+                "publication is for all tables, not altering it"
+            );
+        } else if !same_tables(&tables, &self.published_tables(publication).await?) {
+            self.simple_query(&format!("ALTER PUBLICATION {publication} SET {objects};"))
+                .await?;
+            tracing::info!(publication, "altered published tables");
+        }
+        if existing.publish != config.publish {
+            self.simple_query(&format!(
+                "ALTER PUBLICATION {publication} SET (publish = '{publish}');",
+                publish = config.publish,
+            ))
+            .await?;
+            tracing::info!(publication, "altered published operations");
         }
 
         Ok(())
     }
 
-    async fn table_exists(&self, table: &str) -> anyhow::Result<bool> {
-        let tables = self
+    /// The configured tables by schema and name, including those of the
+    /// configured schemas, failing if one does not exist.
+    async fn configured_tables<'a>(
+        &self,
+        config: &'a ReplicationConfig,
+    ) -> anyhow::Result<BTreeMap<(String, String), Option<&'a PublicationTable>>> {
+        let mut tables = BTreeMap::new();
+        let explicit = config
+            .tables
+            .iter()
+            .map(|table| (table.name.as_str(), Some(table)))
+            .chain(config.watermark_table.as_deref().map(|name| (name, None)));
+        for (name, table) in explicit {
+            let (schema, name) = self
+                .resolve_table(name)
+                .await?
+                .with_context(|| format!("table {name} does not exist"))?;
+            tables.insert((schema, name), table);
+        }
+        for schema in &config.schemas {
+            let result = self
+                .simple_query(&format!(
+                    "SELECT tablename FROM pg_catalog.pg_tables WHERE schemaname = '{schema}';"
+                ))
+                .await?;
+            for row in rows(result) {
+                let name = row.get("tablename").context("missing tablename")?;
+                tables
+                    .entry((schema.clone(), name.to_string()))
+                    .or_insert(None);
+            }
+        }
+        Ok(tables)
+    }
+
+    /// Schema and name of a table, optionally qualified,
+    /// `None` if it does not exist.
+    pub(crate) async fn resolve_table(
+        &self,
+        table: &str,
+    ) -> anyhow::Result<Option<(String, String)>> {
+        let result = self
             .simple_query(&format!(
-                "SELECT * FROM pg_catalog.pg_tables WHERE pg_tables.tablename = '{table}';"
+                r#"
+                SELECT n.nspname, c.relname
+                FROM pg_catalog.pg_class c
+                JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+                WHERE c.oid = to_regclass('{ident}');
+                "#,
+                ident = quote_table(table).replace('\'', "''"),
             ))
             .await?;
-        Ok(tables
-            .into_iter()
-            .find_map(|msg| match msg {
-                tokio_postgres::SimpleQueryMessage::Row(row) => Some(row),
-                _ => None,
-            })
-            .is_some())
+        let Some(row) = rows(result).next() else {
+            return Ok(None);
+        };
+        let get = |column| {
+            row.get(column)
+                .map(str::to_string)
+                .with_context(|| format!("missing {column}"))
+        };
+        Ok(Some((get("nspname")?, get("relname")?)))
+    }
+
+    pub(crate) async fn publication_exists(&self, publication: &str) -> anyhow::Result<bool> {
+        Ok(self.publication(publication).await?.is_some())
     }
 
-    async fn publication_exists(&self, publication: &str) -> anyhow::Result<bool> {
-        let publications = self
+    pub(crate) async fn drop_publication(&self, publication: &str) -> anyhow::Result<()> {
+        self.simple_query(&format!("DROP PUBLICATION IF EXISTS {publication};"))
+            .await?;
+        Ok(())
+    }
+
+    async fn publication(&self, publication: &str) -> anyhow::Result<Option<Publication>> {
+        let result = self
             .simple_query(&format!(
-                "SELECT * FROM pg_publication WHERE pubname = '{publication}';"
+                r#"
+                SELECT puballtables, pubinsert, pubupdate, pubdelete, pubtruncate
+                FROM pg_catalog.pg_publication
+                WHERE pubname = '{publication}';
+                "#
             ))
             .await?;
-        Ok(publications
-            .into_iter()
-            .find_map(|msg| match msg {
-                tokio_postgres::SimpleQueryMessage::Row(row) => Some(row),
-                _ => None,
-            })
-            .is_some())
+        Ok(rows(result).next().map(|row| {
+            let flag = |column| row.get(column) == Some("t");
+            Publication {
+                all_tables: flag("puballtables"),
+                publish: PublishOperations {
+                    insert: flag("pubinsert"),
+                    update: flag("pubupdate"),
+                    delete: flag("pubdelete"),
+                    truncate: flag("pubtruncate"),
+                },
+            }
+        }))
     }
 
-    async fn replication_slot_exists(&self, slot: &str) -> anyhow::Result<bool> {
-        let publications = self
+    /// The tables of the publication, by schema and name.
+    ///
+    /// Columns and row filters are only known from Postgres 15, which
+    /// introduced them.
+    async fn published_tables(
+        &self,
+        publication: &str,
+    ) -> anyhow::Result<BTreeMap<(String, String), PublishedTable>> {
+        let result = self
             .simple_query(&format!(
                 r#"
-                SELECT *
-                FROM pg_replication_slots
-                WHERE slot_name = '{slot}'
-                AND database = '{db}';
-                "#,
-                db = self.dbname,
-                slot = slot,
+                SELECT
+                    t.schemaname,
+                    t.tablename,
+                    to_jsonb(t) -> 'attnames' AS columns,
+                    to_jsonb(t) ->> 'rowfilter' AS row_filter,
+                    NOT EXISTS (
+                        SELECT 1
+                        FROM pg_catalog.pg_attribute a
+                        WHERE a.attrelid = format('%I.%I', t.schemaname, t.tablename)::regclass
+                            AND a.attnum > 0
+                            AND NOT a.attisdropped
+                            AND a.attgenerated = ''
+                            AND NOT COALESCE((to_jsonb(t) -> 'attnames') ? a.attname, true)
+                    ) AS all_columns
+                FROM pg_catalog.pg_publication_tables t
+                WHERE t.pubname = '{publication}';
+                "#
             ))
             .await?;
-        Ok(publications
-            .into_iter()
-            .find_map(|msg| match msg {
-                tokio_postgres::SimpleQueryMessage::Row(row) => Some(row),
-                _ => None,
+        rows(result)
+            .map(|row| {
+                let get = |column| row.get(column).with_context(|| format!("missing {column}"));
+                let mut columns = row
+                    .get("columns")
+                    .map(serde_json::from_str::<Vec<String>>)
+                    .transpose()?
+                    .unwrap_or_default();
+                columns.sort();
+                Ok((
+                    (
+                        get("schemaname")?.to_string(),
+                        get("tablename")?.to_string(),
+                    ),
+                    PublishedTable {
+                        columns,
+                        all_columns: row.get("all_columns") == Some("t"),
+                        row_filter: row.get("row_filter").map(str::to_string),
+                    },
+                ))
             })
-            .is_some())
+            .collect()
+    }
+}
+
+/// Whether a missing replication slot may be created, see
+/// [`ReplicationConfig::failover`].
+///
+/// A failover slot is created along with its publication, so if the
+/// publication already existed, the slot was lost, e.g. as it had not been
+/// synchronized to the standby before it was promoted.
+pub(crate) fn check_missing_slot(
+    config: &ReplicationConfig,
+    publication_existed: bool,
+) -> crate::Result<()> {
+    if config.failover && publication_existed {
+        tracing::warn!(
+            slot = config.replication_slot,
+            "replication slot is missing although its publication exists"
+        );
+        return Err(Error::SlotNotSynchronized {
+            slot: config.replication_slot.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// An existing publication.
+struct Publication {
+    all_tables: bool,
+    publish: PublishOperations,
+}
+
+struct PublishedTable {
+    /// Sorted
+    columns: Vec<String>,
+    all_columns: bool,
+    row_filter: Option<String>,
+}
+
+/// Whether the publication publishes the configured tables.
+///
+/// Row filters are compared as written, so one which Postgres stores
+/// differently, e.g. with casts, is set again on each start.
+fn same_tables(
+    configured: &BTreeMap<(String, String), Option<&PublicationTable>>,
+    published: &BTreeMap<(String, String), PublishedTable>,
+) -> bool {
+    configured.len() == published.len()
+        && configured.iter().all(|(key, table)| {
+            let Some(published) = published.get(key) else {
+                return false;
+            };
+            let columns = table.and_then(|table| table.columns.as_ref());
+            let same_columns = match columns {
+                None => published.all_columns,
+                Some(columns) => {
+                    let mut columns = columns.clone();
+                    columns.sort();
+                    columns == published.columns
+                }
+            };
+            let row_filter = table.and_then(|table| table.row_filter.as_deref());
+            same_columns
+                && row_filter.map(normalize_expression)
+                    == published.row_filter.as_deref().map(normalize_expression)
+        })
+}
+
+/// Without whitespace and enclosing parentheses, which Postgres adds.
+fn normalize_expression(expression: &str) -> String {
+    let mut expression = expression.split_whitespace().collect::<Vec<_>>().join(" ");
+    while expression.starts_with('(') && expression.ends_with(')') && {
+        // Only if they enclose the whole expression, unlike in `(a) AND (b)`
+        let mut depth = 0;
+        expression[..expression.len() - 1].chars().all(|c| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            depth > 0
+        })
+    } {
+        expression = expression[1..expression.len() - 1].trim().to_string();
     }
+    expression
 }
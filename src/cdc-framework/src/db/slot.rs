@@ -0,0 +1,276 @@
+use anyhow::Context;
+use tokio_postgres::{types::PgLsn, SimpleQueryMessage, SimpleQueryRow};
+
+use super::rows;
+use crate::Error;
+
+/// First key of the advisory locks of [`ReplicationConfig::leader_election`],
+/// the second one being derived from the slot.
+///
+/// [`ReplicationConfig::leader_election`]: super::ReplicationConfig::leader_election
+const LEADER_LOCK: i32 = 0xCDC;
+
+/// A replication slot, as listed by [`DbClient::replication_slots`](super::DbClient::replication_slots).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationSlot {
+    pub name: String,
+    /// Output plugin, `None` for physical slots.
+    pub plugin: Option<String>,
+    /// Database of a logical slot.
+    pub database: Option<String>,
+    /// Dropped once the session which created it ends.
+    pub temporary: bool,
+    /// Process streaming from the slot, `None` if inactive.
+    pub active_pid: Option<i32>,
+    /// Oldest WAL position the slot may still need, which Postgres retains.
+    pub restart_lsn: Option<PgLsn>,
+    /// Position up to which the consumer has confirmed changes,
+    /// `None` for physical slots.
+    pub confirmed_flush_lsn: Option<PgLsn>,
+    /// WAL retained for the slot, from its `restart_lsn` up to the
+    /// current position of the server.
+    pub retained_wal_bytes: Option<u64>,
+    /// Whether the WAL the slot needs is still available.
+    pub wal_status: Option<WalStatus>,
+    /// Synchronized to standbys, see [`ReplicationConfig::failover`].
+    /// `None` before Postgres 17.
+    ///
+    /// [`ReplicationConfig::failover`]: super::ReplicationConfig::failover
+    pub failover: Option<bool>,
+    /// Synchronized from the primary, when connected to a standby
+    /// or a promoted one. `None` before Postgres 17.
+    pub synced: Option<bool>,
+}
+
+/// Availability of the WAL a slot needs, see `max_slot_wal_keep_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalStatus {
+    /// Within `max_wal_size`.
+    Reserved,
+    /// Beyond `max_wal_size`, but still retained for the slot.
+    Extended,
+    /// No longer retained, and about to be removed.
+    Unreserved,
+    /// Removed, the slot can no longer be used.
+    Lost,
+}
+
+impl WalStatus {
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "reserved" => Some(Self::Reserved),
+            "extended" => Some(Self::Extended),
+            "unreserved" => Some(Self::Unreserved),
+            "lost" => Some(Self::Lost),
+            _ => None,
+        }
+    }
+}
+
+impl ReplicationSlot {
+    fn from_row(row: &SimpleQueryRow) -> anyhow::Result<Self> {
+        let text = |column: &str| -> anyhow::Result<Option<String>> {
+            Ok(row.try_get(column)?.map(str::to_string))
+        };
+        let lsn = |column: &str| -> anyhow::Result<Option<PgLsn>> {
+            text(column)?.as_deref().map(parse_lsn).transpose()
+        };
+        Ok(Self {
+            name: text("slot_name")?.context("missing slot_name")?,
+            plugin: text("plugin")?,
+            database: text("database")?,
+            temporary: text("temporary")?.as_deref() == Some("t"),
+            active_pid: text("active_pid")?.map(|pid| pid.parse()).transpose()?,
+            restart_lsn: lsn("restart_lsn")?,
+            confirmed_flush_lsn: lsn("confirmed_flush_lsn")?,
+            retained_wal_bytes: text("retained_wal_bytes")?
+                .map(|bytes| bytes.parse())
+                .transpose()?,
+            wal_status: text("wal_status")?.as_deref().and_then(WalStatus::parse),
+            failover: text("failover")?.map(|failover| failover == "true"),
+            synced: text("synced")?.map(|synced| synced == "true"),
+        })
+    }
+}
+
+/// Slots are managed with SQL functions, which work on
+/// replication connections as well.
+impl<const REPLICATION: bool> super::DbClient<REPLICATION> {
+    /// All replication slots of the server, logical and physical
+    /// (requires Postgres 13+).
+    pub async fn replication_slots(&self) -> crate::Result<Vec<ReplicationSlot>> {
+        self.query_slots(None).await.map_err(Error::Setup)
+    }
+
+    pub async fn replication_slot(&self, slot: &str) -> crate::Result<Option<ReplicationSlot>> {
+        let slots = self.query_slots(Some(slot)).await.map_err(Error::Setup)?;
+        Ok(slots.into_iter().next())
+    }
+
+    /// Drops a replication slot, which must not be active.
+    ///
+    /// Postgres then no longer retains WAL for it, and its consumer
+    /// can no longer resume from it.
+    pub async fn drop_replication_slot(&self, slot: &str) -> crate::Result<()> {
+        self.simple_query(&format!("SELECT pg_drop_replication_slot('{slot}');"))
+            .await
+            .map_err(|e| Error::Setup(e.into()))?;
+        Ok(())
+    }
+
+    /// Moves a replication slot forward to `lsn`, skipping the changes
+    /// before it, e.g. to get past a change its consumer cannot handle.
+    ///
+    /// The slot must not be active. Returns the position it was moved to,
+    /// which is at most the current position of the server.
+    pub async fn advance_replication_slot(&self, slot: &str, lsn: PgLsn) -> crate::Result<PgLsn> {
+        let result = self
+            .simple_query(&format!(
+                "SELECT end_lsn FROM pg_replication_slot_advance('{slot}', '{lsn}');"
+            ))
+            .await
+            .map_err(|e| Error::Setup(e.into()))?;
+        first_lsn(result, "end_lsn").map_err(Error::Setup)
+    }
+
+    /// Creates a logical replication slot which is dropped once this
+    /// connection closes, returning the position it starts streaming from.
+    ///
+    /// A subscriber using this connection streams from it, e.g. to
+    /// follow changes without leaving a slot behind.
+    pub async fn create_temporary_replication_slot(&self, slot: &str) -> crate::Result<PgLsn> {
+        let result = self
+            .simple_query(&format!(
+                "SELECT lsn FROM pg_create_logical_replication_slot('{slot}', 'pgoutput', true);"
+            ))
+            .await
+            .map_err(|e| Error::Setup(e.into()))?;
+        first_lsn(result, "lsn").map_err(Error::Setup)
+    }
+
+    pub(crate) async fn create_replication_slot(
+        &self,
+        slot: &str,
+        failover: bool,
+    ) -> anyhow::Result<()> {
+        self.simple_query(&format!(
+            r#"
+            CREATE_REPLICATION_SLOT "{slot}"
+            LOGICAL "pgoutput" {options};
+            "#,
+            options = create_options(false, failover),
+        ))
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn replication_slot_exists(&self, slot: &str) -> anyhow::Result<bool> {
+        let result = self
+            .simple_query(&format!(
+                r#"
+                SELECT *
+                FROM pg_replication_slots
+                WHERE slot_name = '{slot}'
+                AND database = '{db}';
+                "#,
+                db = self.dbname,
+                slot = slot,
+            ))
+            .await?;
+        Ok(rows(result).next().is_some())
+    }
+
+    /// Waits for the lock of the slot, which is then held until this
+    /// connection closes, see [`ReplicationConfig::leader_election`].
+    ///
+    /// [`ReplicationConfig::leader_election`]: super::ReplicationConfig::leader_election
+    pub(crate) async fn lock_slot(&self, slot: &str) -> anyhow::Result<()> {
+        let key = format!("{LEADER_LOCK}, hashtext('{slot}')");
+        let result = self
+            .simple_query(&format!("SELECT pg_try_advisory_lock({key}) AS locked;"))
+            .await?;
+        let locked = rows(result)
+            .next()
+            .context("lock slot: empty rows")?
+            .get("locked")
+            == Some("t");
+        if !locked {
+            tracing::info!(slot, "waiting for the subscriber streaming from the slot");
+            self.simple_query(&format!("SELECT pg_advisory_lock({key});"))
+                .await?;
+        }
+        tracing::info!(slot, "took the lead");
+        Ok(())
+    }
+
+    async fn query_slots(&self, slot: Option<&str>) -> anyhow::Result<Vec<ReplicationSlot>> {
+        let condition = slot
+            .map(|slot| format!("WHERE slot_name = '{slot}'"))
+            .unwrap_or_default();
+        let result = self
+            .simple_query(&format!(
+                r#"
+                SELECT
+                    slot_name,
+                    plugin,
+                    database,
+                    temporary,
+                    active_pid,
+                    restart_lsn,
+                    confirmed_flush_lsn,
+                    pg_wal_lsn_diff(
+                        CASE WHEN pg_is_in_recovery()
+                        THEN pg_last_wal_receive_lsn()
+                        ELSE pg_current_wal_lsn() END,
+                        restart_lsn
+                    )::bigint AS retained_wal_bytes,
+                    wal_status,
+                    -- Missing before Postgres 17
+                    to_jsonb(s) ->> 'failover' AS failover,
+                    to_jsonb(s) ->> 'synced' AS synced
+                FROM pg_replication_slots s
+                {condition}
+                ORDER BY slot_name;
+                "#
+            ))
+            .await?;
+        rows(result)
+            .map(|row| ReplicationSlot::from_row(&row))
+            .collect()
+    }
+}
+
+/// Options of `CREATE_REPLICATION_SLOT`, using the slot's snapshot in the
+/// current transaction or not. Failover slots require the newer syntax.
+pub(super) fn create_options(use_snapshot: bool, failover: bool) -> String {
+    match (use_snapshot, failover) {
+        (true, true) => "(SNAPSHOT 'use', FAILOVER true)".into(),
+        (false, true) => "(SNAPSHOT 'nothing', FAILOVER true)".into(),
+        (true, false) => "USE_SNAPSHOT".into(),
+        (false, false) => "NOEXPORT_SNAPSHOT".into(),
+    }
+}
+
+impl super::DbClient<true> {
+    pub(crate) async fn enable_failover(&self, slot: &str) -> anyhow::Result<()> {
+        self.simple_query(&format!(
+            r#"ALTER_REPLICATION_SLOT "{slot}" (FAILOVER true);"#
+        ))
+        .await?;
+        Ok(())
+    }
+}
+
+/// The LSN in `column` of the first row.
+fn first_lsn(result: Vec<SimpleQueryMessage>, column: &str) -> anyhow::Result<PgLsn> {
+    let row = rows(result).next().context("empty rows")?;
+    parse_lsn(
+        row.get(column)
+            .with_context(|| format!("missing {column}"))?,
+    )
+}
+
+fn parse_lsn(lsn: &str) -> anyhow::Result<PgLsn> {
+    lsn.parse()
+        .map_err(|_| anyhow::anyhow!("failed to parse LSN {lsn}"))
+}
@@ -0,0 +1,310 @@
+use anyhow::Context;
+use bytes::Bytes;
+use postgres_replication::protocol::TupleData;
+use tokio_postgres::{
+    types::{Oid, PgLsn},
+    SimpleQueryRow,
+};
+
+use super::{quote_table, rows, slot::create_options, Column, Relation, ReplicationConfig};
+
+/// A table to read in a snapshot, restricted like in the publication.
+pub(crate) struct SnapshotTable {
+    /// Quoted, possibly qualified name
+    ident: String,
+    columns: Option<Vec<String>>,
+    row_filter: Option<String>,
+}
+
+impl SnapshotTable {
+    pub(crate) fn new(config: &ReplicationConfig, name: &str) -> Self {
+        let table = config.tables.iter().find(|table| table.name == name);
+        Self {
+            ident: quote_table(name),
+            columns: table.and_then(|table| table.columns.clone()),
+            row_filter: table.and_then(|table| table.row_filter.clone()),
+        }
+    }
+
+    /// The table's OID as an SQL expression.
+    fn regclass(&self) -> String {
+        format!("'{}'::regclass", self.ident.replace('\'', "''"))
+    }
+
+    fn where_clause(&self, condition: Option<String>) -> String {
+        let conditions = self
+            .row_filter
+            .iter()
+            .map(|row_filter| format!("({row_filter})"))
+            .chain(condition)
+            .collect::<Vec<_>>();
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        }
+    }
+}
+
+impl super::DbClient<true> {
+    /// Creates the replication slot in a new transaction using the slot's
+    /// snapshot, returning the position the slot starts streaming from.
+    ///
+    /// The transaction has to be committed or rolled back once the
+    /// snapshot has been read.
+    pub(crate) async fn begin_snapshot(&self, slot: &str, failover: bool) -> anyhow::Result<PgLsn> {
+        self.simple_query("BEGIN READ ONLY ISOLATION LEVEL REPEATABLE READ;")
+            .await?;
+        let result = self
+            .simple_query(&format!(
+                r#"
+                CREATE_REPLICATION_SLOT "{slot}"
+                LOGICAL "pgoutput" {options};
+                "#,
+                options = create_options(true, failover),
+            ))
+            .await?;
+        rows(result)
+            .next()
+            .context("create replication slot: empty rows")?
+            .get("consistent_point")
+            .context("missing consistent_point")?
+            .parse()
+            .map_err(|_| anyhow::anyhow!("failed to parse LSN"))
+    }
+
+    /// The published tables, including those of the published schemas.
+    pub(crate) async fn snapshot_tables(
+        &self,
+        config: &ReplicationConfig,
+    ) -> anyhow::Result<Vec<SnapshotTable>> {
+        let mut tables = config
+            .tables
+            .iter()
+            .map(|table| SnapshotTable::new(config, &table.name))
+            .collect::<Vec<_>>();
+
+        let watermark_table = match &config.watermark_table {
+            Some(table) => self.resolve_table(table).await?,
+            None => None,
+        };
+        for schema in &config.schemas {
+            let result = self
+                .simple_query(&format!(
+                    "SELECT tablename FROM pg_catalog.pg_tables WHERE schemaname = '{schema}';"
+                ))
+                .await?;
+            for row in rows(result) {
+                let table = row.get("tablename").context("missing tablename")?;
+                if watermark_table
+                    .as_ref()
+                    .is_some_and(|(s, t)| s == schema && t == table)
+                {
+                    continue;
+                }
+                tables.push(SnapshotTable {
+                    ident: format!(r#""{schema}"."{table}""#),
+                    columns: None,
+                    row_filter: None,
+                });
+            }
+        }
+        Ok(tables)
+    }
+}
+
+impl<const REPLICATION: bool> super::DbClient<REPLICATION> {
+    /// Looks up a table's columns in the catalog, like `pgoutput` sends them.
+    pub(crate) async fn snapshot_relation(
+        &self,
+        table: &SnapshotTable,
+    ) -> anyhow::Result<Relation> {
+        let result = self
+            .simple_query(&format!(
+                r#"
+                SELECT
+                    c.oid,
+                    n.nspname,
+                    c.relname,
+                    a.attname,
+                    a.atttypid,
+                    c.relreplident = 'f' OR COALESCE(a.attnum = ANY(i.indkey), false) AS is_key
+                FROM pg_catalog.pg_class c
+                JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+                JOIN pg_catalog.pg_attribute a ON a.attrelid = c.oid
+                LEFT JOIN pg_catalog.pg_index i ON i.indrelid = c.oid
+                    AND (i.indisreplident OR (c.relreplident = 'd' AND i.indisprimary))
+                WHERE c.oid = {regclass}
+                    AND a.attnum > 0
+                    AND NOT a.attisdropped
+                    AND a.attgenerated = ''
+                ORDER BY a.attnum;
+                "#,
+                regclass = table.regclass(),
+            ))
+            .await?;
+
+        let mut relation = None;
+        let mut columns = vec![];
+        for row in rows(result) {
+            let get = |column| row.get(column).with_context(|| format!("missing {column}"));
+            if relation.is_none() {
+                relation = Some((
+                    get("oid")?.parse::<Oid>()?,
+                    get("nspname")?.to_string(),
+                    get("relname")?.to_string(),
+                ));
+            }
+            let name = get("attname")?;
+            if table
+                .columns
+                .as_ref()
+                .is_some_and(|columns| !columns.iter().any(|c| c == name))
+            {
+                continue;
+            }
+            columns.push(Column::new(
+                name.to_string(),
+                get("atttypid")?.parse()?,
+                get("is_key")? == "t",
+            ));
+        }
+
+        let (id, namespace, name) =
+            relation.with_context(|| format!("table {} has no columns", table.ident))?;
+        Ok(Relation::new(id, namespace, name, columns))
+    }
+
+    /// Opens a cursor over the rows of a table, selecting the columns
+    /// of its relation, to be read with [`Self::fetch`].
+    pub(crate) async fn declare_cursor(
+        &self,
+        cursor: &str,
+        table: &SnapshotTable,
+        relation: &Relation,
+    ) -> anyhow::Result<()> {
+        let columns = relation
+            .columns()
+            .iter()
+            .map(|column| format!(r#""{}""#, column.name()))
+            .collect::<Vec<_>>();
+        self.simple_query(&format!(
+            "DECLARE {cursor} CURSOR FOR SELECT {columns} FROM {ident} {where_clause};",
+            columns = columns.join(", "),
+            ident = table.ident,
+            where_clause = table.where_clause(None),
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Reads the next rows of a cursor in text format,
+    /// returning no rows once the cursor is exhausted.
+    pub(crate) async fn fetch(
+        &self,
+        cursor: &str,
+        count: usize,
+    ) -> anyhow::Result<Vec<Vec<TupleData>>> {
+        let result = self
+            .simple_query(&format!("FETCH {count} FROM {cursor};"))
+            .await?;
+        Ok(rows(result).map(|row| tuple(&row)).collect())
+    }
+
+    pub(crate) async fn close_cursor(&self, cursor: &str) -> anyhow::Result<()> {
+        self.simple_query(&format!("CLOSE {cursor};")).await?;
+        Ok(())
+    }
+
+    /// The columns of a table's primary key, in order.
+    pub(crate) async fn primary_key(&self, table: &SnapshotTable) -> anyhow::Result<Vec<String>> {
+        let result = self
+            .simple_query(&format!(
+                r#"
+                SELECT a.attname
+                FROM pg_catalog.pg_index i
+                CROSS JOIN LATERAL unnest(i.indkey) WITH ORDINALITY AS k(attnum, n)
+                JOIN pg_catalog.pg_attribute a
+                    ON a.attrelid = i.indrelid AND a.attnum = k.attnum
+                WHERE i.indrelid = {regclass} AND i.indisprimary
+                ORDER BY k.n;
+                "#,
+                regclass = table.regclass(),
+            ))
+            .await?;
+        let columns = rows(result)
+            .map(|row| {
+                row.get("attname")
+                    .map(ToString::to_string)
+                    .context("missing attname")
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::ensure!(
+            !columns.is_empty(),
+            "table {} has no primary key",
+            table.ident
+        );
+        Ok(columns)
+    }
+
+    /// Reads the next rows of a table in primary key order, in text format.
+    ///
+    /// `after` is the primary key of the last row read, in text format.
+    pub(crate) async fn select_chunk(
+        &self,
+        table: &SnapshotTable,
+        relation: &Relation,
+        primary_key: &[String],
+        after: Option<&[String]>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Vec<TupleData>>> {
+        let quote = |column: &str| format!(r#""{column}""#);
+        let columns = relation
+            .columns()
+            .iter()
+            .map(|column| quote(column.name()))
+            .collect::<Vec<_>>();
+        let primary_key = primary_key
+            .iter()
+            .map(|column| quote(column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let after = after.map(|after| {
+            let values = after
+                .iter()
+                .map(|value| format!("'{}'", value.replace('\'', "''")))
+                .collect::<Vec<_>>();
+            format!("({primary_key}) > ({})", values.join(", "))
+        });
+
+        let result = self
+            .simple_query(&format!(
+                "SELECT {columns} FROM {ident} {where_clause} ORDER BY {primary_key} LIMIT {limit};",
+                columns = columns.join(", "),
+                ident = table.ident,
+                where_clause = table.where_clause(after),
+            ))
+            .await?;
+        Ok(rows(result).map(|row| tuple(&row)).collect())
+    }
+}
+
+fn tuple(row: &SimpleQueryRow) -> Vec<TupleData> {
+    (0..row.len())
+        .map(|i| match row.get(i) {
+            Some(value) => TupleData::Text(Bytes::copy_from_slice(value.as_bytes())),
+            None => TupleData::Null,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_are_escaped_in_the_regclass() {
+        let table = SnapshotTable::new(&ReplicationConfig::default(), "s.o'brien");
+        assert_eq!(table.regclass(), r#"'"s"."o''brien"'::regclass"#);
+    }
+}
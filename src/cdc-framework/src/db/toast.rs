@@ -0,0 +1,128 @@
+use std::error::Error;
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use postgres_replication::protocol::TupleData;
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use super::{Format, Row};
+
+impl<const REPLICATION: bool> super::DbClient<REPLICATION> {
+    /// Looks up the current values of some columns of a row by its replica
+    /// identity, in the format of the row. `None` if the row no longer exists.
+    pub(crate) async fn lookup_columns(
+        &self,
+        row: &Row<'_>,
+        columns: &[usize],
+    ) -> anyhow::Result<Option<Vec<TupleData>>> {
+        let relation = row.relation();
+        let quote = |column: &str| format!(r#""{column}""#);
+
+        // Text values are read as `text`, whose binary format is its text format
+        let selected = columns
+            .iter()
+            .map(|&i| {
+                let column = quote(relation.columns()[i].name());
+                match row.format() {
+                    Format::Text => format!("{column}::text"),
+                    Format::Binary => column,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut conditions = vec![];
+        let mut types = vec![];
+        let mut params = vec![];
+        for column in relation.columns().iter().filter(|column| column.is_key()) {
+            let TupleData::Text(value) = row.raw(column.name())?.1 else {
+                anyhow::bail!("column {}: missing key value", column.name());
+            };
+            let ty = Type::from_oid(column.type_oid()).with_context(|| {
+                format!(
+                    "column {}: unknown type OID {}",
+                    column.name(),
+                    column.type_oid()
+                )
+            })?;
+            types.push(ty);
+            params.push(RawParam {
+                value: value.clone(),
+                format: row.format(),
+            });
+            conditions.push(format!("{} = ${}", quote(column.name()), params.len()));
+        }
+        anyhow::ensure!(
+            !conditions.is_empty(),
+            "table {} has no replica identity",
+            relation.name()
+        );
+
+        let statement = self
+            .prepare_typed(
+                &format!(
+                    r#"SELECT {} FROM "{}"."{}" WHERE {};"#,
+                    selected.join(", "),
+                    relation.namespace(),
+                    relation.name(),
+                    conditions.join(" AND "),
+                ),
+                &types,
+            )
+            .await?;
+        let params = params
+            .iter()
+            .map(|param| param as &(dyn ToSql + Sync))
+            .collect::<Vec<_>>();
+        let Some(result) = self.query_opt(&statement, &params).await? else {
+            return Ok(None);
+        };
+
+        let values = (0..columns.len())
+            .map(|i| match result.try_get::<_, Option<RawValue>>(i)? {
+                Some(RawValue(value)) => Ok(TupleData::Text(value)),
+                None => Ok(TupleData::Null),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(values))
+    }
+}
+
+/// A value sent as is, in the format it was received in.
+#[derive(Debug)]
+struct RawParam {
+    value: Bytes,
+    format: Format,
+}
+
+impl ToSql for RawParam {
+    fn to_sql(&self, _: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.extend_from_slice(&self.value);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _: &Type) -> tokio_postgres::types::Format {
+        match self.format {
+            Format::Text => tokio_postgres::types::Format::Text,
+            Format::Binary => tokio_postgres::types::Format::Binary,
+        }
+    }
+
+    to_sql_checked!();
+}
+
+/// A value in binary format, whatever its type.
+struct RawValue(Bytes);
+
+impl<'a> FromSql<'a> for RawValue {
+    fn from_sql(_: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(Self(Bytes::copy_from_slice(raw)))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
@@ -0,0 +1,279 @@
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::Context;
+use bytes::Buf;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use tokio_postgres::types::{FromSql, Type};
+use uuid::Uuid;
+
+/// Format of the column values sent by `pgoutput`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    /// Values in the types' binary send format, see the `binary` option of
+    /// [`ReplicationConfig`](super::ReplicationConfig).
+    Binary,
+}
+
+/// A column value decoded according to its type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Text(String),
+    Bytea(Vec<u8>),
+    Uuid(Uuid),
+    /// `infinity` and `-infinity` are [`DateTime::<Utc>::MAX_UTC`]
+    /// and [`DateTime::<Utc>::MIN_UTC`].
+    TimestampTz(DateTime<Utc>),
+    Jsonb(serde_json::Value),
+    /// Decimal representation, to avoid losing precision.
+    Numeric(String),
+    /// A TOASTed value not changed by an UPDATE, and thus not sent,
+    /// see [`UnchangedToast`](super::UnchangedToast).
+    UnchangedToast,
+}
+
+impl Value {
+    pub fn decode(ty: &Type, format: Format, raw: &[u8]) -> anyhow::Result<Self> {
+        match format {
+            Format::Text => Self::from_text(ty, std::str::from_utf8(raw)?),
+            Format::Binary => Self::from_binary(ty, raw),
+        }
+    }
+
+    fn from_text(ty: &Type, s: &str) -> anyhow::Result<Self> {
+        let value = match *ty {
+            Type::BOOL => Self::Bool(s == "t"),
+            Type::INT2 => Self::Int2(s.parse()?),
+            Type::INT4 => Self::Int4(s.parse()?),
+            Type::INT8 => Self::Int8(s.parse()?),
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => Self::Text(s.into()),
+            Type::BYTEA => {
+                let hex = s
+                    .strip_prefix("\\x")
+                    .context("expected hex-encoded bytea")?;
+                Self::Bytea(hex::decode(hex)?)
+            }
+            Type::UUID => Self::Uuid(Uuid::from_str(s)?),
+            Type::TIMESTAMPTZ => Self::TimestampTz(timestamptz_from_text(s)?),
+            Type::JSONB | Type::JSON => Self::Jsonb(serde_json::from_str(s)?),
+            Type::NUMERIC => Self::Numeric(s.into()),
+            _ => anyhow::bail!("unsupported type {ty}"),
+        };
+        Ok(value)
+    }
+
+    fn from_binary(ty: &Type, raw: &[u8]) -> anyhow::Result<Self> {
+        let value = match *ty {
+            Type::BOOL => Self::Bool(from_sql(ty, raw)?),
+            Type::INT2 => Self::Int2(from_sql(ty, raw)?),
+            Type::INT4 => Self::Int4(from_sql(ty, raw)?),
+            Type::INT8 => Self::Int8(from_sql(ty, raw)?),
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => {
+                Self::Text(from_sql(ty, raw)?)
+            }
+            Type::BYTEA => Self::Bytea(raw.to_vec()),
+            Type::UUID => Self::Uuid(from_sql(ty, raw)?),
+            Type::TIMESTAMPTZ => Self::TimestampTz(timestamptz_from_binary(ty, raw)?),
+            Type::JSONB | Type::JSON => Self::Jsonb(from_sql(ty, raw)?),
+            Type::NUMERIC => Self::Numeric(numeric_from_binary(raw)?),
+            _ => anyhow::bail!("unsupported type {ty}"),
+        };
+        Ok(value)
+    }
+}
+
+fn from_sql<'a, T: FromSql<'a>>(ty: &Type, raw: &'a [u8]) -> anyhow::Result<T> {
+    T::from_sql(ty, raw).map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Parses a `timestamptz` in the ISO output format, whose offset has seconds
+/// in local mean time, e.g. `1900-01-01 00:19:32+00:19:32`.
+fn timestamptz_from_text(s: &str) -> anyhow::Result<DateTime<Utc>> {
+    match s {
+        "infinity" => return Ok(DateTime::<Utc>::MAX_UTC),
+        "-infinity" => return Ok(DateTime::<Utc>::MIN_UTC),
+        _ => {}
+    }
+    let (s, bc) = match s.strip_suffix(" BC") {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    // The date's dashes come before the offset
+    let (datetime, offset) = s.split_at(
+        s.rfind(['+', '-'])
+            .with_context(|| format!("missing offset in timestamp {s}"))?,
+    );
+    let mut datetime = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S%.f")?;
+    if bc {
+        // 1 BC is year 0
+        datetime = datetime
+            .with_year(1 - datetime.year())
+            .with_context(|| format!("invalid timestamp {s} BC"))?;
+    }
+    let mut seconds = 0;
+    for (part, unit) in offset[1..].split(':').zip([3600, 60, 1]) {
+        seconds += part.parse::<i32>()? * unit;
+    }
+    if offset.starts_with('-') {
+        seconds = -seconds;
+    }
+    let offset =
+        FixedOffset::east_opt(seconds).with_context(|| format!("invalid offset {offset}"))?;
+    offset
+        .from_local_datetime(&datetime)
+        .single()
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .with_context(|| format!("invalid timestamp {s}"))
+}
+
+fn timestamptz_from_binary(ty: &Type, raw: &[u8]) -> anyhow::Result<DateTime<Utc>> {
+    match raw.try_into().map(i64::from_be_bytes) {
+        Ok(i64::MAX) => Ok(DateTime::<Utc>::MAX_UTC),
+        Ok(i64::MIN) => Ok(DateTime::<Utc>::MIN_UTC),
+        _ => from_sql(ty, raw),
+    }
+}
+
+/// Formats a binary `numeric`, which is a sequence of base 10000 digits.
+///
+/// See `numeric_send` in <https://github.com/postgres/postgres/blob/master/src/backend/utils/adt/numeric.c>.
+fn numeric_from_binary(mut raw: &[u8]) -> anyhow::Result<String> {
+    const NUMERIC_NEG: u16 = 0x4000;
+    const NUMERIC_NAN: u16 = 0xC000;
+    const NUMERIC_PINF: u16 = 0xD000;
+    const NUMERIC_NINF: u16 = 0xF000;
+
+    anyhow::ensure!(raw.remaining() >= 8, "invalid numeric");
+    let ndigits = raw.get_i16();
+    let weight = raw.get_i16();
+    let sign = raw.get_u16();
+    let dscale = raw.get_u16() as usize;
+    anyhow::ensure!(
+        ndigits >= 0 && raw.remaining() == ndigits as usize * 2,
+        "invalid numeric"
+    );
+    let digits = (0..ndigits).map(|_| raw.get_i16()).collect::<Vec<_>>();
+
+    match sign {
+        NUMERIC_NAN => return Ok("NaN".into()),
+        NUMERIC_PINF => return Ok("Infinity".into()),
+        NUMERIC_NINF => return Ok("-Infinity".into()),
+        _ => {}
+    }
+
+    // Digit i is multiplied by 10000^(weight - i)
+    let digit = |i: i32| {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| digits.get(i))
+            .copied()
+            .unwrap_or(0)
+    };
+
+    let mut s = String::new();
+    if sign == NUMERIC_NEG {
+        s.push('-');
+    }
+    if weight < 0 {
+        s.push('0');
+    } else {
+        write!(s, "{}", digit(0))?;
+        for i in 1..=weight as i32 {
+            write!(s, "{:04}", digit(i))?;
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut i = weight as i32 + 1;
+        while fraction.len() < dscale {
+            write!(fraction, "{:04}", digit(i))?;
+            i += 1;
+        }
+        fraction.truncate(dscale);
+        write!(s, ".{fraction}")?;
+    }
+    Ok(s)
+}
+
+/// Types that can be extracted from a [`Value`].
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> anyhow::Result<Self>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> anyhow::Result<Self> {
+        Ok(value)
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> anyhow::Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+/// A value which may be an unchanged TOASTed value, e.g. to tell
+/// a column that did not change apart from one that is null.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Toast<T> {
+    Value(T),
+    Unchanged,
+}
+
+impl<T> Toast<T> {
+    /// `None` if unchanged.
+    pub fn value(self) -> Option<T> {
+        match self {
+            Self::Value(x) => Some(x),
+            Self::Unchanged => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Toast<T> {
+    fn from_value(value: Value) -> anyhow::Result<Self> {
+        match value {
+            Value::UnchangedToast => Ok(Self::Unchanged),
+            value => T::from_value(value).map(Self::Value),
+        }
+    }
+}
+
+macro_rules! impl_from_value {
+    ($($variant:ident => $ty:ty),* $(,)?) => {
+        $(
+            impl FromValue for $ty {
+                fn from_value(value: Value) -> anyhow::Result<Self> {
+                    match value {
+                        Value::$variant(x) => Ok(x),
+                        value => anyhow::bail!(
+                            "expected {}, got {value:?}",
+                            stringify!($variant),
+                        ),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_from_value! {
+    Bool => bool,
+    Int2 => i16,
+    Int4 => i32,
+    Int8 => i64,
+    Text => String,
+    Bytea => Vec<u8>,
+    Uuid => Uuid,
+    TimestampTz => DateTime<Utc>,
+    Jsonb => serde_json::Value,
+}
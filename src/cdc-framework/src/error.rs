@@ -0,0 +1,100 @@
+use tokio_postgres::types::PgLsn;
+
+/// What went wrong, for callers to react accordingly, e.g. restart on
+/// connection loss but dead-letter changes which cannot be decoded.
+///
+/// The sources keep the context of the failure.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Connecting to Postgres failed, or the connection was lost. Also when
+    /// the replication slot is still in use, e.g. by a connection which has
+    /// not been closed yet.
+    #[error("connection to Postgres failed")]
+    Connection(#[source] anyhow::Error),
+    /// Setting up the publication, the replication slot or replication
+    /// failed, e.g. for lack of permissions or because a table is missing.
+    #[error("setup failed")]
+    Setup(#[source] anyhow::Error),
+    /// A message sent by Postgres could not be parsed.
+    #[error("unexpected replication message")]
+    Protocol(#[source] anyhow::Error),
+    /// A row could not be decoded into an entity.
+    #[error("could not decode a row of {table} at {lsn}")]
+    Decode {
+        table: String,
+        lsn: PgLsn,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// A handler failed, its transaction has not been ACKed.
+    #[error("handler failed")]
+    Handler(#[source] anyhow::Error),
+    /// Reporting the processed position to Postgres failed.
+    #[error("could not ACK {lsn}")]
+    Ack {
+        lsn: PgLsn,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// The initial or an incremental snapshot failed, see
+    /// [`ReplicationConfig::snapshot`](crate::db::ReplicationConfig::snapshot)
+    /// and [`SnapshotHandle`](crate::SnapshotHandle).
+    #[error("snapshot failed")]
+    Snapshot(#[source] anyhow::Error),
+    /// The replication slot was dropped for retaining too much WAL,
+    /// see [`WalAction::Recreate`](crate::WalAction::Recreate).
+    #[error("dropped replication slot {slot} retaining {retained_bytes} bytes of WAL")]
+    WalRetention { slot: String, retained_bytes: u64 },
+    /// The replication slot is missing on a promoted standby or unusable,
+    /// see [`ReplicationConfig::failover`](crate::db::ReplicationConfig::failover).
+    #[error("replication slot {slot} was not synchronized to the promoted standby")]
+    SlotNotSynchronized { slot: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Whether the same work may succeed when retried, unlike setup,
+    /// protocol and decode errors.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Connection(_)
+            | Self::Handler(_)
+            | Self::Ack { .. }
+            | Self::Snapshot(_)
+            | Self::WalRetention { .. } => true,
+            Self::Setup(_)
+            | Self::Protocol(_)
+            | Self::Decode { .. }
+            | Self::SlotNotSynchronized { .. } => false,
+        }
+    }
+
+    /// Keeps an [`Error`] raised further down, e.g. by a dispatcher decoding
+    /// rows, or classifies the error as `kind`.
+    pub(crate) fn or(error: anyhow::Error, kind: fn(anyhow::Error) -> Self) -> Self {
+        match error.downcast::<Self>() {
+            Ok(error) => error,
+            Err(error) => kind(error),
+        }
+    }
+
+    /// The error followed by its sources, like `{:#}` for [`anyhow::Error`].
+    pub(crate) fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            report.push_str(&format!(": {error}"));
+            source = error.source();
+        }
+        report
+    }
+
+    pub(crate) fn decode(table: &str, lsn: PgLsn, source: anyhow::Error) -> Self {
+        Self::Decode {
+            table: table.to_string(),
+            lsn,
+            source,
+        }
+    }
+}
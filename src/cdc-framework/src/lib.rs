@@ -1,6 +1,23 @@
 pub mod db;
+mod error;
+pub mod metrics;
 mod publisher;
 mod subscriber;
 
+pub use error::{Error, Result};
+pub use metrics::Metrics;
 pub use publisher::Publisher;
-pub use subscriber::{handler::EventHandler, Subscriber};
+pub use subscriber::{
+    dispatch::{Dispatcher, EventDispatch, TransactionDispatch},
+    event::{ChangeEvent, Envelope, Metadata, OldRow, Transaction, TransactionInfo},
+    handler::{
+        handler_fn, BoxEventHandler, DynEventHandler, EventHandler, HandlerFn, TransactionHandler,
+    },
+    layer::{self, HandlerBuilder, Layer},
+    router::{Routed, Router},
+    safeguard::{WalAction, WalCheck, WalLevel, WalSafeguard},
+    shutdown::ShutdownHandle,
+    supervisor::{Backoff, Restart, Supervisor},
+    watermark::SnapshotHandle,
+    Subscriber,
+};
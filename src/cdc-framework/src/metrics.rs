@@ -0,0 +1,279 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio_postgres::types::PgLsn;
+
+/// Upper bounds of the handler duration buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Counters and gauges of a subscriber, shared by its clones.
+///
+/// Pass the same instance to a [`Subscriber`](crate::Subscriber) or
+/// [`Supervisor`](crate::Supervisor) and to handlers recording retries,
+/// and expose it with [`Metrics::render`].
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Replication slot, used as label
+    slot: OnceLock<String>,
+    events_handled: AtomicU64,
+    events_failed: AtomicU64,
+    transactions: AtomicU64,
+    retries: AtomicU64,
+    restarts: AtomicU64,
+    in_flight: AtomicI64,
+    acked_lsn: AtomicU64,
+    server_lsn: AtomicU64,
+    /// Not cumulative, see [`BUCKETS`], the last one being `+Inf`
+    duration_buckets: [AtomicU64; BUCKETS.len() + 1],
+    duration_sum_micros: AtomicU64,
+}
+
+impl Inner {
+    fn lag_bytes(&self) -> u64 {
+        let server_lsn = self.server_lsn.load(Ordering::Relaxed);
+        server_lsn.saturating_sub(self.acked_lsn.load(Ordering::Relaxed))
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Changes handed to handlers which succeeded.
+    pub fn events_handled(&self) -> u64 {
+        self.inner.events_handled.load(Ordering::Relaxed)
+    }
+
+    /// Changes handed to handlers which failed.
+    pub fn events_failed(&self) -> u64 {
+        self.inner.events_failed.load(Ordering::Relaxed)
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.inner.retries.load(Ordering::Relaxed)
+    }
+
+    /// Handlers currently running.
+    pub fn in_flight(&self) -> i64 {
+        self.inner.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Position up to which changes have been ACKed.
+    pub fn acked_lsn(&self) -> PgLsn {
+        self.inner.acked_lsn.load(Ordering::Relaxed).into()
+    }
+
+    /// WAL written by the server but not ACKed yet, as of the
+    /// last message received.
+    pub fn lag_bytes(&self) -> u64 {
+        self.inner.lag_bytes()
+    }
+
+    /// Called by handlers which retry a change later on.
+    pub fn record_retry(&self) {
+        self.inner.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_restart(&self) {
+        self.inner.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_slot(&self, slot: &str) {
+        // Keeps the first slot if shared by several subscribers
+        let _ = self.inner.slot.set(slot.to_string());
+    }
+
+    pub(crate) fn record_transaction(&self) {
+        self.inner.transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_acked_lsn(&self, lsn: PgLsn) {
+        self.inner.acked_lsn.store(lsn.into(), Ordering::Relaxed);
+    }
+
+    /// Records the end of the server's WAL, as sent with each message.
+    pub(crate) fn record_server_lsn(&self, lsn: u64) {
+        self.inner.server_lsn.fetch_max(lsn, Ordering::Relaxed);
+    }
+
+    /// Runs a handler handling `events` changes, recording its duration and outcome.
+    pub(crate) async fn measure<F>(&self, events: usize, handler: F) -> anyhow::Result<()>
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let inner = &self.inner;
+        inner.in_flight.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let result = handler.await;
+        inner.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        self.observe(started.elapsed());
+        let counter = match result {
+            Ok(()) => &inner.events_handled,
+            Err(_) => &inner.events_failed,
+        };
+        counter.fetch_add(events as u64, Ordering::Relaxed);
+        result
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(BUCKETS.len());
+        self.inner.duration_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .duration_sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        render(std::slice::from_ref(self))
+    }
+
+    fn labels(&self, extra: Option<(&str, &str)>) -> String {
+        let labels = self
+            .inner
+            .slot
+            .get()
+            .map(|slot| ("slot", slot.as_str()))
+            .into_iter()
+            .chain(extra)
+            .map(|(name, value)| format!(r#"{name}="{}""#, value.replace('"', r#"\""#)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+}
+
+/// Renders the metrics of several subscribers in the Prometheus text
+/// format, telling them apart by their replication slot.
+pub fn render(metrics: &[Metrics]) -> String {
+    type Sample = fn(&Inner) -> u64;
+    let samples: [(&str, &str, &str, Sample); 8] = [
+        (
+            "cdc_events_handled_total",
+            "counter",
+            "Changes handled successfully.",
+            |m| m.events_handled.load(Ordering::Relaxed),
+        ),
+        (
+            "cdc_events_failed_total",
+            "counter",
+            "Changes whose handler failed.",
+            |m| m.events_failed.load(Ordering::Relaxed),
+        ),
+        (
+            "cdc_transactions_total",
+            "counter",
+            "Transactions handled and ACKed.",
+            |m| m.transactions.load(Ordering::Relaxed),
+        ),
+        (
+            "cdc_retries_total",
+            "counter",
+            "Changes retried by handlers.",
+            |m| m.retries.load(Ordering::Relaxed),
+        ),
+        (
+            "cdc_restarts_total",
+            "counter",
+            "Restarts of the subscriber.",
+            |m| m.restarts.load(Ordering::Relaxed),
+        ),
+        (
+            "cdc_handlers_in_flight",
+            "gauge",
+            "Handlers currently running.",
+            |m| m.in_flight.load(Ordering::Relaxed).max(0) as u64,
+        ),
+        (
+            "cdc_acked_lsn",
+            "gauge",
+            "Position up to which changes have been ACKed.",
+            |m| m.acked_lsn.load(Ordering::Relaxed),
+        ),
+        (
+            "cdc_slot_lag_bytes",
+            "gauge",
+            "WAL written by the server but not ACKed yet.",
+            Inner::lag_bytes,
+        ),
+    ];
+
+    let mut s = String::new();
+    for (name, kind, help, sample) in samples {
+        let _ = writeln!(s, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for m in metrics {
+            let _ = writeln!(s, "{name}{} {}", m.labels(None), sample(&m.inner));
+        }
+    }
+
+    let name = "cdc_handler_duration_seconds";
+    let _ = writeln!(
+        s,
+        "# HELP {name} Time spent in handlers.\n# TYPE {name} histogram"
+    );
+    for m in metrics {
+        let mut count = 0;
+        for (i, bucket) in m.inner.duration_buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = BUCKETS.get(i).map_or("+Inf".to_string(), f64::to_string);
+            let _ = writeln!(s, "{name}_bucket{} {count}", m.labels(Some(("le", &le))));
+        }
+        let sum = m.inner.duration_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(s, "{name}_sum{} {sum}", m.labels(None));
+        let _ = writeln!(s, "{name}_count{} {count}", m.labels(None));
+    }
+    s
+}
+
+/// Serves the metrics in the Prometheus text format, whatever the path.
+///
+/// Only returns if accepting connections fails.
+#[cfg(feature = "prometheus")]
+pub async fn serve_prometheus(
+    listener: tokio::net::TcpListener,
+    metrics: Vec<Metrics>,
+) -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let metrics = Arc::new(metrics);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // The request does not matter, but has to be read before responding
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let body = render(&metrics);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/plain; version=0.0.4\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
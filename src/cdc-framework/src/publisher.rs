@@ -11,7 +11,7 @@ pub struct Publisher<T: Entity> {
 }
 
 impl<T: Entity> Publisher<T> {
-    pub async fn new(db_client: db::DbClient) -> anyhow::Result<Self> {
+    pub async fn new(db_client: db::DbClient) -> crate::Result<Self> {
         Ok(Self {
             client: Arc::new(RwLock::new(db_client)),
             t: std::marker::PhantomData,
@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use super::{
+    event::{ChangeEvent, Envelope, Transaction, TransactionInfo},
+    handler::{EventHandler, TransactionHandler},
+};
+use crate::{
+    db::{Dispatch, Entity},
+    metrics::Metrics,
+};
+
+/// Which changes have to be handled before a given change.
+enum Partition {
+    /// None, the change can be handled right away.
+    None,
+    /// Previous changes with the same key.
+    Key(u64),
+    /// All previous changes.
+    All,
+}
+
+impl Partition {
+    fn of<T: Entity>(dispatch: Dispatch, change: &ChangeEvent<T>) -> Self {
+        if dispatch == Dispatch::Concurrent {
+            return Self::None;
+        }
+        let entity = match change {
+            ChangeEvent::Insert(new)
+            | ChangeEvent::Update { new, .. }
+            | ChangeEvent::Snapshot(new) => new,
+            ChangeEvent::Delete(old) => old.as_inner(),
+            ChangeEvent::Truncate { .. } => return Self::All,
+        };
+        match entity.partition_key() {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                Self::Key(hasher.finish())
+            }
+            None => Self::None,
+        }
+    }
+}
+
+/// Hands the changes of each transaction to a handler.
+///
+/// Implemented by [`EventDispatch`] for [`EventHandler`]s, and by
+/// [`TransactionDispatch`] for [`TransactionHandler`]s.
+pub trait Dispatcher<T: Entity>: Send + 'static {
+    /// Called for each change of the current transaction, in commit order.
+    fn push(&mut self, change: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Called once the current transaction committed, it is ACKed as
+    /// soon as this returns successfully.
+    fn commit(
+        &mut self,
+        transaction: TransactionInfo,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Called when shutting down, the current transaction will be sent again.
+    fn close(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Called by the subscriber before any change, for the dispatcher
+    /// to record how its handlers perform.
+    fn set_metrics(&mut self, _metrics: Metrics) {}
+}
+
+/// Hands changes to an [`EventHandler`] one by one, as they arrive.
+///
+/// Handlers run in the background according to the [`Dispatch`] mode,
+/// the transaction is only ACKed once all of them finished.
+pub struct EventDispatch<H> {
+    handler: Arc<H>,
+    dispatch: Dispatch,
+    metrics: Metrics,
+    unordered: Vec<JoinHandle<anyhow::Result<()>>>,
+    /// Last change of each partition, which waits for the one before it
+    partitions: HashMap<u64, JoinHandle<anyhow::Result<()>>>,
+}
+
+impl<H> EventDispatch<H> {
+    pub fn new(handler: Arc<H>, dispatch: Dispatch) -> Self {
+        Self {
+            handler,
+            dispatch,
+            metrics: Metrics::default(),
+            unordered: vec![],
+            partitions: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn set_dispatch(&mut self, dispatch: Dispatch) {
+        self.dispatch = dispatch;
+    }
+
+    /// Waits for all handlers to finish, failing if any of them failed.
+    async fn join(&mut self) -> anyhow::Result<()> {
+        let handles = self
+            .unordered
+            .drain(..)
+            .chain(self.partitions.drain().map(|(_, handle)| handle));
+        futures::future::try_join_all(handles)
+            .await
+            .context("handler panicked")?
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Clones share the handler, but not the handlers in flight.
+impl<H> Clone for EventDispatch<H> {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: self.metrics.clone(),
+            ..Self::new(self.handler.clone(), self.dispatch)
+        }
+    }
+}
+
+impl<T, H> Dispatcher<T> for EventDispatch<H>
+where
+    T: Entity,
+    H: EventHandler<T> + Send + Sync + 'static,
+{
+    /// Handles a change in the background, unless it has to wait
+    /// for all previous changes.
+    async fn push(&mut self, change: Envelope<T>) -> anyhow::Result<()> {
+        let partition = Partition::of(self.dispatch, &change.change);
+        let span = tracing::debug_span!(
+            "event",
+            table = change.metadata.relation.name(),
+            lsn = %change.metadata.lsn,
+            operation = change.change.operation(),
+        );
+        let handler = self.handler.clone();
+        let metrics = self.metrics.clone();
+        let fut = async move { metrics.measure(1, handler.handle(change)).await }.instrument(span);
+
+        match partition {
+            Partition::None => self.unordered.push(tokio::spawn(fut)),
+            Partition::Key(key) => {
+                let previous = self.partitions.remove(&key);
+                let handle = tokio::spawn(async move {
+                    if let Some(previous) = previous {
+                        previous.await??;
+                    }
+                    fut.await
+                });
+                self.partitions.insert(key, handle);
+            }
+            Partition::All => {
+                self.join().await?;
+                fut.await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn commit(&mut self, _transaction: TransactionInfo) -> anyhow::Result<()> {
+        self.join().await
+    }
+
+    /// Lets in-flight handlers finish.
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.join().await
+    }
+
+    fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+}
+
+/// Collects the changes of each transaction and hands them to a
+/// [`TransactionHandler`] once it committed, skipping transactions
+/// without changes.
+pub struct TransactionDispatch<T, H> {
+    handler: Arc<H>,
+    metrics: Metrics,
+    changes: Vec<Envelope<T>>,
+}
+
+impl<T, H> TransactionDispatch<T, H> {
+    pub fn new(handler: Arc<H>) -> Self {
+        Self {
+            handler,
+            metrics: Metrics::default(),
+            changes: vec![],
+        }
+    }
+}
+
+/// Clones share the handler, but not the uncommitted changes.
+impl<T, H> Clone for TransactionDispatch<T, H> {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: self.metrics.clone(),
+            ..Self::new(self.handler.clone())
+        }
+    }
+}
+
+impl<T, H> Dispatcher<T> for TransactionDispatch<T, H>
+where
+    T: Entity,
+    H: TransactionHandler<T> + Send + Sync + 'static,
+{
+    async fn push(&mut self, change: Envelope<T>) -> anyhow::Result<()> {
+        self.changes.push(change);
+        Ok(())
+    }
+
+    async fn commit(&mut self, info: TransactionInfo) -> anyhow::Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        let changes = std::mem::take(&mut self.changes);
+        self.metrics
+            .measure(
+                changes.len(),
+                self.handler.handle(Transaction { info, changes }),
+            )
+            .await
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        self.changes.clear();
+        Ok(())
+    }
+
+    fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+}
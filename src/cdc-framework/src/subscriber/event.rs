@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use tokio_postgres::types::PgLsn;
+
+use crate::db::Relation;
+
+/// A change to a row of the replicated table.
+#[derive(Debug, Clone)]
+pub enum ChangeEvent<T> {
+    Insert(T),
+    /// `old` is only sent if the table has `REPLICA IDENTITY FULL`,
+    /// or if the update modified the replica identity key.
+    Update {
+        old: Option<OldRow<T>>,
+        new: T,
+    },
+    Delete(OldRow<T>),
+    Truncate {
+        cascade: bool,
+        restart_identity: bool,
+    },
+    /// An existing row, read by the initial snapshot of the tables, see
+    /// [`ReplicationConfig::snapshot`](crate::db::ReplicationConfig::snapshot),
+    /// or by an incremental snapshot, see [`SnapshotHandle`](crate::SnapshotHandle).
+    ///
+    /// For the initial snapshot, its metadata carry the position the slot
+    /// starts streaming from as LSNs, and 0 as xid. For incremental snapshots,
+    /// they are those of the write of the high watermark.
+    Snapshot(T),
+}
+
+impl<T> ChangeEvent<T> {
+    /// e.g. `"insert"`, for logs and metrics.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            Self::Insert(_) => "insert",
+            Self::Update { .. } => "update",
+            Self::Delete(_) => "delete",
+            Self::Truncate { .. } => "truncate",
+            Self::Snapshot(_) => "snapshot",
+        }
+    }
+
+    /// The state of the row after the change, if it still exists.
+    pub fn after(&self) -> Option<&T> {
+        match self {
+            Self::Insert(new) | Self::Update { new, .. } | Self::Snapshot(new) => Some(new),
+            _ => None,
+        }
+    }
+
+    pub fn into_after(self) -> Option<T> {
+        match self {
+            Self::Insert(new) | Self::Update { new, .. } | Self::Snapshot(new) => Some(new),
+            _ => None,
+        }
+    }
+
+    /// Converts the rows of the change.
+    pub fn try_map<U, E>(self, mut f: impl FnMut(T) -> Result<U, E>) -> Result<ChangeEvent<U>, E> {
+        let change = match self {
+            Self::Insert(new) => ChangeEvent::Insert(f(new)?),
+            Self::Update { old, new } => ChangeEvent::Update {
+                old: old.map(|old| old.try_map(&mut f)).transpose()?,
+                new: f(new)?,
+            },
+            Self::Delete(old) => ChangeEvent::Delete(old.try_map(f)?),
+            Self::Snapshot(row) => ChangeEvent::Snapshot(f(row)?),
+            Self::Truncate {
+                cascade,
+                restart_identity,
+            } => ChangeEvent::Truncate {
+                cascade,
+                restart_identity,
+            },
+        };
+        Ok(change)
+    }
+}
+
+/// The state of a row before an UPDATE or DELETE.
+#[derive(Debug, Clone)]
+pub enum OldRow<T> {
+    /// Only the replica identity columns are set, all other columns are null.
+    Key(T),
+    /// The complete row, sent for tables with `REPLICA IDENTITY FULL`.
+    Full(T),
+}
+
+impl<T> OldRow<T> {
+    pub fn as_inner(&self) -> &T {
+        match self {
+            Self::Key(x) | Self::Full(x) => x,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Key(x) | Self::Full(x) => x,
+        }
+    }
+
+    pub fn try_map<U, E>(self, f: impl FnOnce(T) -> Result<U, E>) -> Result<OldRow<U>, E> {
+        match self {
+            Self::Key(x) => f(x).map(OldRow::Key),
+            Self::Full(x) => f(x).map(OldRow::Full),
+        }
+    }
+}
+
+/// A change together with where it comes from.
+#[derive(Debug, Clone)]
+pub struct Envelope<T> {
+    pub change: ChangeEvent<T>,
+    pub metadata: Metadata,
+}
+
+/// Where a change comes from.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    /// WAL position of the change, increasing within a transaction, e.g. to
+    /// deduplicate changes sent again after a restart. The tables truncated
+    /// by a single TRUNCATE share the same position.
+    pub lsn: PgLsn,
+    pub xid: u32,
+    /// LSN of the transaction's commit record.
+    pub commit_lsn: PgLsn,
+    pub commit_time: DateTime<Utc>,
+    /// The changed table.
+    pub relation: Arc<Relation>,
+    /// See [`TransactionInfo::origin`].
+    pub origin: Option<Arc<str>>,
+}
+
+/// Metadata of a committed transaction, from its BEGIN and COMMIT messages.
+#[derive(Debug, Clone)]
+pub struct TransactionInfo {
+    pub xid: u32,
+    /// LSN of the commit record.
+    pub commit_lsn: PgLsn,
+    /// LSN right after the commit record, up to which the transaction is ACKed.
+    pub end_lsn: PgLsn,
+    pub commit_time: DateTime<Utc>,
+    /// Replication origin the transaction was applied with, e.g. by a sink
+    /// applying changes from another database, see
+    /// [`DbClient::setup_replication_origin`](crate::db::DbClient::setup_replication_origin).
+    pub origin: Option<Arc<str>>,
+}
+
+/// All the changes of a committed transaction, in commit order.
+#[derive(Debug, Clone)]
+pub struct Transaction<T> {
+    pub info: TransactionInfo,
+    pub changes: Vec<Envelope<T>>,
+}
@@ -0,0 +1,55 @@
+use crate::{
+    db::{check_missing_slot, DbClient, ReplicationConfig, WalStatus},
+    metrics::Metrics,
+    Error,
+};
+
+/// Checks the replication slot before streaming from it,
+/// see [`ReplicationConfig::failover`].
+///
+/// On a promoted standby the slot must have been synchronized from the
+/// primary, otherwise it would be created anew, missing changes. A missing
+/// slot is only created for a new publication, see [`check_missing_slot`].
+pub(crate) async fn check_slot(
+    client: &DbClient<true>,
+    config: &ReplicationConfig,
+    publication_existed: bool,
+    metrics: &Metrics,
+) -> crate::Result<()> {
+    let not_synchronized = || Error::SlotNotSynchronized {
+        slot: config.replication_slot.clone(),
+    };
+    let Some(slot) = client.replication_slot(&config.replication_slot).await? else {
+        return check_missing_slot(config, publication_existed);
+    };
+
+    if slot.synced == Some(true) {
+        // Synchronized slots can only be streamed from once promoted
+        let confirmed_flush_lsn = slot
+            .confirmed_flush_lsn
+            .filter(|_| slot.wal_status != Some(WalStatus::Lost))
+            .ok_or_else(not_synchronized)?;
+
+        // Changes ACKed since the slot was last synchronized are streamed again
+        let acked_lsn = metrics.acked_lsn();
+        if confirmed_flush_lsn < acked_lsn {
+            tracing::warn!(
+                %confirmed_flush_lsn,
+                %acked_lsn,
+                "synchronized slot is behind, changes are delivered again"
+            );
+        }
+        tracing::info!(
+            slot = slot.name,
+            %confirmed_flush_lsn,
+            "resuming from synchronized slot"
+        );
+    } else if slot.failover == Some(false) {
+        client
+            .enable_failover(&config.replication_slot)
+            .await
+            .map_err(Error::Setup)?;
+        tracing::info!(slot = config.replication_slot, "enabled failover");
+    }
+    Ok(())
+}
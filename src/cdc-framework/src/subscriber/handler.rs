@@ -1,7 +1,93 @@
-use std::future::Future;
+use std::{future::Future, sync::Arc};
 
+use futures::future::BoxFuture;
+
+use super::event::{Envelope, Transaction};
 use crate::db::Entity;
 
 pub trait EventHandler<T: Entity> {
-    fn handle(&self, msg: T) -> impl Future<Output = anyhow::Result<()>> + Send;
+    fn handle(&self, msg: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Receives each committed transaction as a whole, e.g. to publish
+/// its changes atomically.
+pub trait TransactionHandler<T: Entity> {
+    fn handle(
+        &self,
+        transaction: Transaction<T>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+impl<T: Entity, H: EventHandler<T> + ?Sized> EventHandler<T> for &H {
+    fn handle(&self, msg: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        (**self).handle(msg)
+    }
+}
+
+impl<T: Entity, H: EventHandler<T> + ?Sized> EventHandler<T> for Arc<H> {
+    fn handle(&self, msg: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        (**self).handle(msg)
+    }
+}
+
+impl<T: Entity, H: EventHandler<T> + ?Sized> EventHandler<T> for Box<H> {
+    fn handle(&self, msg: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        (**self).handle(msg)
+    }
+}
+
+/// A handler calling a function returning a future,
+/// e.g. `handler_fn(|msg| async move { .. })`.
+///
+/// Closures cannot implement [`EventHandler`] directly, as that would
+/// conflict with the implementations for references, [`Arc`] and [`Box`].
+pub fn handler_fn<T, F, Fut>(f: F) -> HandlerFn<F>
+where
+    T: Entity,
+    F: Fn(Envelope<T>) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send,
+{
+    HandlerFn(f)
+}
+
+/// See [`handler_fn`].
+#[derive(Clone, Copy)]
+pub struct HandlerFn<F>(F);
+
+impl<T, F, Fut> EventHandler<T> for HandlerFn<F>
+where
+    T: Entity,
+    F: Fn(Envelope<T>) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send,
+{
+    fn handle(&self, msg: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        (self.0)(msg)
+    }
+}
+
+/// An [`EventHandler`] which can be used as a trait object, implemented
+/// for all event handlers. Usually held as a [`BoxEventHandler`].
+pub trait DynEventHandler<T: Entity> {
+    fn handle_boxed(&self, msg: Envelope<T>) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+impl<T: Entity, H: EventHandler<T>> DynEventHandler<T> for H {
+    fn handle_boxed(&self, msg: Envelope<T>) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(self.handle(msg))
+    }
+}
+
+/// A type-erased [`EventHandler`], e.g. to choose handlers at runtime.
+pub struct BoxEventHandler<T>(Box<dyn DynEventHandler<T> + Send + Sync>);
+
+impl<T: Entity> BoxEventHandler<T> {
+    pub fn new(handler: impl EventHandler<T> + Send + Sync + 'static) -> Self {
+        Self(Box::new(handler))
+    }
+}
+
+impl<T: Entity> EventHandler<T> for BoxEventHandler<T> {
+    fn handle(&self, msg: Envelope<T>) -> impl Future<Output = anyhow::Result<()>> + Send {
+        self.0.handle_boxed(msg)
+    }
 }
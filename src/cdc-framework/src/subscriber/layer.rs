@@ -0,0 +1,295 @@
+//! Composable middleware for [`EventHandler`]s, in the style of Tower.
+//!
+//! A [`Layer`] wraps a handler into another one, and a [`HandlerBuilder`]
+//! stacks layers, the first one added being the outermost:
+//!
+//! ```ignore
+//! let handler = HandlerBuilder::new()
+//!     .filter(|msg: &Envelope<EventRecord>| {
+//!         msg.change.after().is_some_and(|event| event.event_type == "order_placed")
+//!     })
+//!     .layer(EagerRetryLayer::new(client))
+//!     .timeout(Duration::from_secs(5))
+//!     .handler(publisher);
+//! ```
+
+use std::time::Duration;
+
+use anyhow::Context;
+
+use super::{event::Envelope, handler::EventHandler};
+use crate::db::Entity;
+
+/// Wraps a handler, e.g. to filter, retry or instrument its messages.
+pub trait Layer<H> {
+    type Handler;
+
+    fn layer(&self, inner: H) -> Self::Handler;
+}
+
+/// Stacks [`Layer`]s around a handler.
+#[derive(Debug, Clone)]
+pub struct HandlerBuilder<L> {
+    layer: L,
+}
+
+impl HandlerBuilder<Identity> {
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl Default for HandlerBuilder<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> HandlerBuilder<L> {
+    /// Adds a layer, wrapped by the ones added before.
+    pub fn layer<T>(self, layer: T) -> HandlerBuilder<Stack<T, L>> {
+        HandlerBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    /// See [`FilterLayer`].
+    pub fn filter<F>(self, predicate: F) -> HandlerBuilder<Stack<FilterLayer<F>, L>> {
+        self.layer(FilterLayer::new(predicate))
+    }
+
+    /// See [`MapLayer`].
+    pub fn map<F>(self, f: F) -> HandlerBuilder<Stack<MapLayer<F>, L>> {
+        self.layer(MapLayer::new(f))
+    }
+
+    /// See [`InspectLayer`].
+    pub fn inspect<F>(self, f: F) -> HandlerBuilder<Stack<InspectLayer<F>, L>> {
+        self.layer(InspectLayer::new(f))
+    }
+
+    /// See [`TimeoutLayer`].
+    pub fn timeout(self, timeout: Duration) -> HandlerBuilder<Stack<TimeoutLayer, L>> {
+        self.layer(TimeoutLayer::new(timeout))
+    }
+
+    /// Wraps the handler with the layers.
+    pub fn handler<H>(&self, handler: H) -> L::Handler
+    where
+        L: Layer<H>,
+    {
+        self.layer.layer(handler)
+    }
+}
+
+/// A layer returning the handler as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<H> Layer<H> for Identity {
+    type Handler = H;
+
+    fn layer(&self, inner: H) -> H {
+        inner
+    }
+}
+
+/// Two layers, `outer` wrapping `inner`.
+#[derive(Debug, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<H, Inner, Outer> Layer<H> for Stack<Inner, Outer>
+where
+    Inner: Layer<H>,
+    Outer: Layer<Inner::Handler>,
+{
+    type Handler = Outer::Handler;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// Only passes on the messages matching the predicate,
+/// the others being ACKed without being handled.
+#[derive(Clone)]
+pub struct FilterLayer<F> {
+    predicate: F,
+}
+
+impl<F> FilterLayer<F> {
+    pub fn new(predicate: F) -> Self {
+        Self { predicate }
+    }
+}
+
+impl<F: Clone, H> Layer<H> for FilterLayer<F> {
+    type Handler = Filter<F, H>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        Filter {
+            predicate: self.predicate.clone(),
+            inner,
+        }
+    }
+}
+
+/// See [`FilterLayer`].
+#[derive(Clone)]
+pub struct Filter<F, H> {
+    predicate: F,
+    inner: H,
+}
+
+impl<T, F, H> EventHandler<T> for Filter<F, H>
+where
+    T: Entity,
+    F: Fn(&Envelope<T>) -> bool + Send + Sync,
+    H: EventHandler<T> + Send + Sync,
+{
+    async fn handle(&self, msg: Envelope<T>) -> anyhow::Result<()> {
+        if !(self.predicate)(&msg) {
+            tracing::trace!(lsn = %msg.metadata.lsn, "filtered out");
+            return Ok(());
+        }
+        self.inner.handle(msg).await
+    }
+}
+
+/// Converts the messages before passing them on,
+/// possibly to another entity.
+#[derive(Clone)]
+pub struct MapLayer<F> {
+    f: F,
+}
+
+impl<F> MapLayer<F> {
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<F: Clone, H> Layer<H> for MapLayer<F> {
+    type Handler = Map<F, H>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        Map {
+            f: self.f.clone(),
+            inner,
+        }
+    }
+}
+
+/// See [`MapLayer`].
+#[derive(Clone)]
+pub struct Map<F, H> {
+    f: F,
+    inner: H,
+}
+
+impl<T, U, F, H> EventHandler<T> for Map<F, H>
+where
+    T: Entity,
+    U: Entity,
+    F: Fn(Envelope<T>) -> Envelope<U> + Send + Sync,
+    H: EventHandler<U> + Send + Sync,
+{
+    async fn handle(&self, msg: Envelope<T>) -> anyhow::Result<()> {
+        self.inner.handle((self.f)(msg)).await
+    }
+}
+
+/// Calls a function with each message before passing it on,
+/// e.g. for logging.
+#[derive(Clone)]
+pub struct InspectLayer<F> {
+    f: F,
+}
+
+impl<F> InspectLayer<F> {
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<F: Clone, H> Layer<H> for InspectLayer<F> {
+    type Handler = Inspect<F, H>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        Inspect {
+            f: self.f.clone(),
+            inner,
+        }
+    }
+}
+
+/// See [`InspectLayer`].
+#[derive(Clone)]
+pub struct Inspect<F, H> {
+    f: F,
+    inner: H,
+}
+
+impl<T, F, H> EventHandler<T> for Inspect<F, H>
+where
+    T: Entity,
+    F: Fn(&Envelope<T>) + Send + Sync,
+    H: EventHandler<T> + Send + Sync,
+{
+    async fn handle(&self, msg: Envelope<T>) -> anyhow::Result<()> {
+        (self.f)(&msg);
+        self.inner.handle(msg).await
+    }
+}
+
+/// Fails messages which are not handled in time, so that a stuck handler
+/// does not hold up the subscriber.
+///
+/// The inner handler is dropped mid-way, so it must be safe to cancel.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<H> Layer<H> for TimeoutLayer {
+    type Handler = Timeout<H>;
+
+    fn layer(&self, inner: H) -> Self::Handler {
+        Timeout {
+            timeout: self.timeout,
+            inner,
+        }
+    }
+}
+
+/// See [`TimeoutLayer`].
+#[derive(Debug, Clone)]
+pub struct Timeout<H> {
+    timeout: Duration,
+    inner: H,
+}
+
+impl<T, H> EventHandler<T> for Timeout<H>
+where
+    T: Entity,
+    H: EventHandler<T> + Send + Sync,
+{
+    async fn handle(&self, msg: Envelope<T>) -> anyhow::Result<()> {
+        let lsn = msg.metadata.lsn;
+        tokio::time::timeout(self.timeout, self.inner.handle(msg))
+            .await
+            .with_context(|| format!("handler timed out after {:?} at {lsn}", self.timeout))?
+    }
+}
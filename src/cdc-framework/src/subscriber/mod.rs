@@ -1,27 +1,83 @@
 use std::{
+    collections::HashMap,
     pin::Pin,
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use dispatch::{Dispatcher, EventDispatch, TransactionDispatch};
+use event::{ChangeEvent, Envelope, Metadata, OldRow, TransactionInfo};
 use futures::{SinkExt, StreamExt};
-use handler::EventHandler;
-use postgres_replication::protocol::{CommitBody, LogicalReplicationMessage, ReplicationMessage};
-use tokio_postgres::{types::PgLsn, SimpleQueryMessage};
+use handler::{EventHandler, TransactionHandler};
+use postgres_replication::protocol::{
+    LogicalReplicationMessage, ReplicationMessage, Tuple, TupleData,
+};
+use router::{Routed, Router};
+use safeguard::{WalAction, WalSafeguard};
+use shutdown::ShutdownHandle;
+use stream::{Message, StreamedTransactions};
+use tokio::time::MissedTickBehavior;
+use tokio_postgres::{
+    error::SqlState,
+    types::{Oid, PgLsn},
+    SimpleQueryMessage,
+};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::Instrument;
+use watermark::{Chunks, SnapshotHandle, Watermarks};
 
-use crate::db::{self, Entity, ReplicationConfig};
+use crate::{
+    db::{
+        self, copy_tuple_data, Entity, Format, OriginFilter, Relation, ReplicationConfig, Row,
+        UnchangedToast,
+    },
+    metrics::Metrics,
+    Error,
+};
 
+pub mod dispatch;
+pub mod event;
+mod failover;
 pub mod handler;
+pub mod layer;
+pub mod router;
+pub mod safeguard;
+pub mod shutdown;
+mod snapshot;
+mod stream;
+pub mod supervisor;
+pub mod watermark;
 
-pub struct Subscriber<T: Entity, H: EventHandler<T>> {
+/// Streams the changes of a table to a handler, either change by change
+/// ([`Subscriber::new`]) or transaction by transaction ([`Subscriber::transactional`]),
+/// or the changes of several tables to a handler per table ([`Subscriber::routed`]).
+pub struct Subscriber<T: Entity, D: Dispatcher<T>> {
     stream: Pin<Box<tokio_postgres::CopyBothDuplex<bytes::Bytes>>>,
-    message_handler: Arc<H>,
+    dispatcher: D,
+    relations: HashMap<Oid, Arc<Relation>>,
+    format: Format,
+    streamed: StreamedTransactions,
+    /// Everything up to this LSN has been processed
+    lsn: PgLsn,
+    status_interval: Duration,
+    shutdown: ShutdownHandle,
+    metrics: Metrics,
+    /// Span of the current transaction
+    span: tracing::Span,
+    /// Set if incremental snapshots are enabled
+    watermarks: Option<Watermarks>,
+    /// See [`UnchangedToast::Lookup`]
+    lookup_client: Option<db::DbClient>,
+    /// With the client used to check the slot
+    wal_safeguard: Option<(WalSafeguard, Arc<db::DbClient>)>,
+    replication_config: ReplicationConfig,
     t: std::marker::PhantomData<T>,
 }
 
-impl<T, H> Subscriber<T, H>
+impl<T, H> Subscriber<T, EventDispatch<H>>
 where
     T: Entity,
     H: EventHandler<T> + Send + Sync + 'static,
@@ -30,84 +86,835 @@ where
         db_client: &db::DbClient<true>,
         replication_config: &ReplicationConfig,
         message_handler: H,
-    ) -> anyhow::Result<Self> {
-        db_client.setup(replication_config).await?;
-        let lsn = get_start_lsn(db_client, replication_config).await?;
-
-        let stream = db_client
-            .copy_both_simple::<bytes::Bytes>(
-                &(format!(
-                    r#"
-                    START_REPLICATION SLOT {slot}
-                    LOGICAL {lsn}
-                    (
-                        "proto_version" '1',
-                        "publication_names" '{publication}'
-                    );
-                    "#,
+    ) -> crate::Result<Self> {
+        let dispatcher = EventDispatch::new(Arc::new(message_handler), replication_config.dispatch);
+        Self::with_dispatcher(
+            db_client,
+            replication_config,
+            dispatcher,
+            ShutdownHandle::default(),
+            Chunks::default(),
+            Metrics::default(),
+        )
+        .await
+    }
+}
+
+impl<T, H> Subscriber<T, TransactionDispatch<T, H>>
+where
+    T: Entity,
+    H: TransactionHandler<T> + Send + Sync + 'static,
+{
+    /// Hands each committed transaction to the handler as a whole,
+    /// [`ReplicationConfig::dispatch`] does not apply.
+    pub async fn transactional(
+        db_client: &db::DbClient<true>,
+        replication_config: &ReplicationConfig,
+        message_handler: H,
+    ) -> crate::Result<Self> {
+        Self::with_dispatcher(
+            db_client,
+            replication_config,
+            TransactionDispatch::new(Arc::new(message_handler)),
+            ShutdownHandle::default(),
+            Chunks::default(),
+            Metrics::default(),
+        )
+        .await
+    }
+}
+
+impl Subscriber<Routed, Router> {
+    pub async fn routed(
+        db_client: &db::DbClient<true>,
+        replication_config: &ReplicationConfig,
+        mut router: Router,
+    ) -> crate::Result<Self> {
+        router.set_dispatch(replication_config.dispatch);
+        Self::with_dispatcher(
+            db_client,
+            replication_config,
+            router,
+            ShutdownHandle::default(),
+            Chunks::default(),
+            Metrics::default(),
+        )
+        .await
+    }
+}
+
+impl<T: Entity, D: Dispatcher<T>> Subscriber<T, D> {
+    pub(crate) async fn with_dispatcher(
+        db_client: &db::DbClient<true>,
+        replication_config: &ReplicationConfig,
+        mut dispatcher: D,
+        shutdown: ShutdownHandle,
+        chunks: Chunks,
+        metrics: Metrics,
+    ) -> crate::Result<Self> {
+        metrics.set_slot(&replication_config.replication_slot);
+        dispatcher.set_metrics(metrics.clone());
+        if replication_config.leader_election {
+            db_client
+                .lock_slot(&replication_config.replication_slot)
+                .await
+                .map_err(Error::Connection)?;
+        }
+        let publication_existed = db_client
+            .publication_exists(&replication_config.publication)
+            .await
+            .map_err(Error::Setup)?;
+        db_client.setup_publication(replication_config).await?;
+        if replication_config.failover {
+            failover::check_slot(db_client, replication_config, publication_existed, &metrics)
+                .await?;
+        }
+        if !db_client
+            .replication_slot_exists(&replication_config.replication_slot)
+            .await
+            .map_err(Error::Setup)?
+        {
+            if replication_config.snapshot {
+                let result =
+                    snapshot::snapshot(db_client, replication_config, &mut dispatcher).await;
+                if result.is_err() && replication_config.failover && !publication_existed {
+                    // Otherwise the slot would be expected to exist when retried
+                    db_client
+                        .drop_publication(&replication_config.publication)
+                        .await
+                        .map_err(Error::Setup)?;
+                }
+                result?;
+            } else {
+                db_client
+                    .create_replication_slot(
+                        &replication_config.replication_slot,
+                        replication_config.failover,
+                    )
+                    .await
+                    .map_err(Error::Setup)?;
+                tracing::info!(
                     slot = replication_config.replication_slot,
-                    publication = replication_config.publication,
-                )),
-            )
-            .await?;
+                    "created replication slot"
+                );
+            }
+        }
+        // Resolved once, as relations carry their schema
+        let watermark_table = match &replication_config.watermark_table {
+            Some(table) => Some(
+                db_client
+                    .resolve_table(table)
+                    .await
+                    .and_then(|resolved| {
+                        resolved.with_context(|| format!("watermark table {table} does not exist"))
+                    })
+                    .map_err(Error::Setup)?,
+            ),
+            None => None,
+        };
+        let lsn = get_start_lsn(db_client, replication_config)
+            .await
+            .map_err(Error::Setup)?;
+        let stream = start_replication(db_client, replication_config, lsn)
+            .await
+            .map_err(|e| {
+                // e.g. by a connection which is about to be closed
+                if is_sql_state(&e, &SqlState::OBJECT_IN_USE) {
+                    Error::Connection(e.context("replication slot is in use"))
+                } else {
+                    Error::Setup(e)
+                }
+            })?;
+        tracing::info!(
+            slot = replication_config.replication_slot,
+            %lsn,
+            "started replication"
+        );
+        let format = if replication_config.binary {
+            Format::Binary
+        } else {
+            Format::Text
+        };
 
         Ok(Self {
             stream: Box::pin(stream),
-            message_handler: Arc::new(message_handler),
+            dispatcher,
+            relations: HashMap::new(),
+            format,
+            streamed: StreamedTransactions::new(replication_config.streaming_memory),
+            lsn,
+            status_interval: replication_config.status_interval,
+            shutdown,
+            metrics,
+            span: tracing::Span::none(),
+            watermarks: watermark_table
+                .map(|table| Watermarks::new(table, replication_config, format, chunks)),
+            lookup_client: None,
+            wal_safeguard: None,
+            replication_config: replication_config.clone(),
             t: std::marker::PhantomData,
         })
     }
 
-    pub async fn listen(&mut self) -> anyhow::Result<()> {
-        let mut futures = vec![];
-        while let Some(msg) = self.stream.as_mut().next().await {
-            let msg = msg.context("could not get next message in stream")?;
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Records into `metrics` instead, e.g. to share them with handlers.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        metrics.set_slot(&self.replication_config.replication_slot);
+        self.dispatcher.set_metrics(metrics.clone());
+        self.metrics = metrics;
+        self
+    }
+
+    /// Used to look up unchanged TOAST values, see [`UnchangedToast::Lookup`].
+    pub fn with_lookup_client(mut self, db_client: db::DbClient) -> Self {
+        self.lookup_client = Some(db_client);
+        self
+    }
+
+    /// Checks the WAL retained for the slot using `db_client`.
+    pub fn with_wal_safeguard(mut self, safeguard: WalSafeguard, db_client: db::DbClient) -> Self {
+        self.wal_safeguard = Some((safeguard, Arc::new(db_client)));
+        self
+    }
 
-            let ReplicationMessage::XLogData(data) = ReplicationMessage::parse(&msg)? else {
-                continue;
+    /// Requires [`ReplicationConfig::watermark_table`] to be set,
+    /// `db_client` being used to read the tables.
+    pub fn snapshot_handle(&self, db_client: db::DbClient) -> crate::Result<SnapshotHandle> {
+        let chunks = self
+            .watermarks
+            .as_ref()
+            .context("incremental snapshots require a watermark table")
+            .map_err(Error::Setup)?
+            .chunks();
+        SnapshotHandle::new(db_client, &self.replication_config, chunks)
+    }
+
+    /// Fails with an [`Error`] telling what went wrong, the changes of the
+    /// current transaction being streamed again once restarted.
+    pub async fn listen(&mut self) -> crate::Result<()> {
+        let Some((safeguard, client)) = self.wal_safeguard.clone() else {
+            return self.stream_changes().await;
+        };
+        // Checked apart from the stream, which is not read while handlers are stuck
+        let mut wal_check = AbortOnDropHandle::new(tokio::spawn(check_wal(
+            safeguard,
+            client.clone(),
+            self.replication_config.replication_slot.clone(),
+        )));
+        let slot = tokio::select! {
+            result = self.stream_changes() => return result,
+            slot = &mut wal_check => slot
+                .context("WAL safeguard failed")
+                .map_err(Error::Connection)?,
+        };
+
+        // Not waiting for the handlers, which may be stuck
+        self.stream
+            .as_mut()
+            .close()
+            .await
+            .map_err(|e| Error::Connection(e.into()))?;
+        drop_inactive_slot(&client, &slot.name).await?;
+        Err(Error::WalRetention {
+            slot: slot.name,
+            retained_bytes: slot.retained_wal_bytes.unwrap_or(0),
+        })
+    }
+
+    async fn stream_changes(&mut self) -> crate::Result<()> {
+        // Set between BEGIN and COMMIT
+        let mut transaction: Option<TransactionInfo> = None;
+
+        // Regularly report our position, so that Postgres does not consider
+        // the connection dead while no transactions are flowing
+        let mut status_interval = tokio::time::interval(self.status_interval);
+        status_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let shutdown = self.shutdown.clone();
+        loop {
+            let msg = tokio::select! {
+                biased;
+                _ = shutdown.wait() => {
+                    return self.close().await;
+                }
+                _ = status_interval.tick() => {
+                    self.send_status(false).await.map_err(Error::Connection)?;
+                    continue;
+                }
+                msg = self.stream.next() => msg,
+            };
+            let Some(msg) = msg else {
+                break;
             };
+            let msg = msg
+                .context("could not get next message in stream")
+                .map_err(Error::Connection)?;
 
-            match LogicalReplicationMessage::parse(data.data())? {
-                // Process INSERTs in the background
-                LogicalReplicationMessage::Insert(msg) => {
-                    let record = T::from_tuple(msg.tuple())?;
-                    let event_handler = self.message_handler.clone();
-                    futures.push(tokio::spawn(
-                        async move { event_handler.handle(record).await },
-                    ));
-                }
-                // Process UPDATEs in the background
-                LogicalReplicationMessage::Update(msg) => {
-                    let record = T::from_tuple(msg.new_tuple())?;
-                    let event_handler = self.message_handler.clone();
-                    futures.push(tokio::spawn(
-                        async move { event_handler.handle(record).await },
-                    ));
-                }
-                // On COMMIT, finish processing all the INSERTs and UPDATEs
-                // before ACKing the whole transaction
-                LogicalReplicationMessage::Commit(msg) => {
-                    futures::future::try_join_all(std::mem::take(&mut futures))
-                        .await
-                        .context("failed to process msg, aborting")?;
-                    self.ack(msg).await?;
+            let data =
+                match ReplicationMessage::parse(&msg).map_err(|e| Error::Protocol(e.into()))? {
+                    ReplicationMessage::XLogData(data) => {
+                        self.metrics.record_server_lsn(data.wal_end());
+                        data
+                    }
+                    ReplicationMessage::PrimaryKeepAlive(keepalive) => {
+                        self.metrics.record_server_lsn(keepalive.wal_end());
+                        // With no transaction pending, everything up to the
+                        // position of the server has been processed
+                        if transaction.is_none() && self.streamed.is_empty() {
+                            self.lsn = self.lsn.max(PgLsn::from(keepalive.wal_end()));
+                        }
+                        if keepalive.reply() == 1 {
+                            self.send_status(false).await.map_err(Error::Connection)?;
+                        }
+                        continue;
+                    }
+                    _ => {
+                        continue;
+                    }
+                };
+
+            let msg = match Message::parse(data.data(), self.streamed.in_stream(), self.format)
+                .map_err(Error::Protocol)?
+            {
+                Message::Logical(msg) => msg,
+                Message::Streamed { subxid, change } => {
+                    self.streamed
+                        .push(subxid, data.wal_start().into(), change)
+                        .map_err(Error::Protocol)?;
+                    continue;
+                }
+                Message::StreamStart { xid } => {
+                    self.streamed.start(xid);
+                    continue;
+                }
+                Message::StreamStop => {
+                    self.streamed.stop();
+                    continue;
+                }
+                Message::StreamAbort { xid, subxid } => {
+                    self.streamed.abort(xid, subxid);
+                    continue;
                 }
-                _ => {
+                // Streamed changes are only processed once the transaction commits
+                Message::StreamCommit {
+                    xid,
+                    commit_lsn,
+                    end_lsn,
+                    timestamp,
+                } => {
+                    let (origin, changes) = self.streamed.commit(xid).map_err(Error::Protocol)?;
+                    let info = TransactionInfo {
+                        xid,
+                        commit_lsn: commit_lsn.into(),
+                        end_lsn: end_lsn.into(),
+                        commit_time: pg_timestamp(timestamp).map_err(Error::Protocol)?,
+                        origin,
+                    };
+                    self.span = transaction_span(&info);
+                    let mut transaction = Some(info);
+                    for change in changes {
+                        let (lsn, change) = change.map_err(Error::Protocol)?;
+                        let msg = LogicalReplicationMessage::parse(&change)
+                            .map_err(|e| Error::Protocol(e.into()))?;
+                        self.handle(msg, lsn, &mut transaction).await?;
+                    }
+                    let info = transaction
+                        .context("streamed transaction ended early")
+                        .map_err(Error::Protocol)?;
+                    self.commit(info).await?;
                     continue;
                 }
             };
+
+            let lsn = PgLsn::from(data.wal_start());
+            self.handle(msg, lsn, &mut transaction).await?;
         }
         Ok(())
     }
 
-    async fn ack(&mut self, commit: CommitBody) -> anyhow::Result<()> {
-        let ssu = prepare_ssu(PgLsn::from(commit.end_lsn()));
+    /// Handles a message of a transaction, or one describing it,
+    /// `transaction` being set between BEGIN and COMMIT.
+    async fn handle(
+        &mut self,
+        msg: LogicalReplicationMessage,
+        lsn: PgLsn,
+        transaction: &mut Option<TransactionInfo>,
+    ) -> crate::Result<()> {
+        let (rel_id, change, tuples) = match msg {
+            // Keep track of table schemas so that tuples can be decoded by column name
+            LogicalReplicationMessage::Relation(msg) => {
+                let relation = Relation::try_from(&msg).map_err(Error::Protocol)?;
+                self.relations.insert(relation.id(), Arc::new(relation));
+                return Ok(());
+            }
+            LogicalReplicationMessage::Insert(msg) => {
+                if self.is_watermark(msg.rel_id()) {
+                    self.on_watermark(msg.rel_id(), msg.tuple(), lsn, transaction.as_ref())
+                        .await?;
+                    return Ok(());
+                }
+                let new = self.decode(msg.rel_id(), msg.tuple(), lsn)?;
+                let tuples = self.copy_tuples(msg.rel_id(), [Some(msg.tuple())])?;
+                (msg.rel_id(), ChangeEvent::Insert(new), tuples)
+            }
+            LogicalReplicationMessage::Update(msg) => {
+                if self.is_watermark(msg.rel_id()) {
+                    self.on_watermark(msg.rel_id(), msg.new_tuple(), lsn, transaction.as_ref())
+                        .await?;
+                    return Ok(());
+                }
+                let old = self.decode_old(msg.rel_id(), msg.old_tuple(), msg.key_tuple(), lsn)?;
+                let new_data = self
+                    .new_data(msg.rel_id(), msg.old_tuple(), msg.new_tuple())
+                    .await?;
+                let new = self.decode_data(msg.rel_id(), &new_data, lsn)?;
+                let mut tuples =
+                    self.copy_tuples(msg.rel_id(), [msg.old_tuple().or(msg.key_tuple())])?;
+                if self.watermarks.is_some() {
+                    tuples.push(new_data);
+                }
+                (msg.rel_id(), ChangeEvent::Update { old, new }, tuples)
+            }
+            LogicalReplicationMessage::Delete(msg) => {
+                if self.is_watermark(msg.rel_id()) {
+                    return Ok(());
+                }
+                let old = self
+                    .decode_old(msg.rel_id(), msg.old_tuple(), msg.key_tuple(), lsn)?
+                    .context("DELETE without old row or key")
+                    .map_err(Error::Protocol)?;
+                let tuples =
+                    self.copy_tuples(msg.rel_id(), [msg.old_tuple().or(msg.key_tuple())])?;
+                (msg.rel_id(), ChangeEvent::Delete(old), tuples)
+            }
+            // A change per truncated table
+            LogicalReplicationMessage::Truncate(msg) => {
+                for &rel_id in msg.rel_ids() {
+                    if self.is_watermark(rel_id) {
+                        continue;
+                    }
+                    let change = ChangeEvent::Truncate {
+                        cascade: msg.options() & 1 != 0,
+                        restart_identity: msg.options() & 2 != 0,
+                    };
+                    let change = self.pending(lsn, rel_id, change, vec![])?;
+                    self.push(transaction.as_ref(), change).await?;
+                }
+                return Ok(());
+            }
+            LogicalReplicationMessage::Begin(msg) => {
+                let info = TransactionInfo {
+                    xid: msg.xid(),
+                    commit_lsn: msg.final_lsn().into(),
+                    end_lsn: PgLsn::from(0),
+                    commit_time: pg_timestamp(msg.timestamp()).map_err(Error::Protocol)?,
+                    origin: None,
+                };
+                self.span = transaction_span(&info);
+                *transaction = Some(info);
+                return Ok(());
+            }
+            // Sent right after BEGIN, or the first Stream Start
+            LogicalReplicationMessage::Origin(msg) => {
+                let origin = Arc::from(msg.name().map_err(|e| Error::Protocol(e.into()))?);
+                match transaction {
+                    Some(info) => {
+                        self.span.record("origin", &*origin);
+                        info.origin = Some(origin);
+                    }
+                    None => self.streamed.set_origin(origin).map_err(Error::Protocol)?,
+                }
+                return Ok(());
+            }
+            LogicalReplicationMessage::Commit(msg) => {
+                let mut info = transaction
+                    .take()
+                    .context("COMMIT without BEGIN")
+                    .map_err(Error::Protocol)?;
+                info.end_lsn = msg.end_lsn().into();
+                self.commit(info).await?;
+                return Ok(());
+            }
+            _ => {
+                return Ok(());
+            }
+        };
+
+        let change = self.pending(lsn, rel_id, change, tuples)?;
+        self.push(transaction.as_ref(), change).await
+    }
+
+    fn pending(
+        &self,
+        lsn: PgLsn,
+        rel_id: Oid,
+        change: ChangeEvent<T>,
+        tuples: Vec<Vec<TupleData>>,
+    ) -> crate::Result<PendingChange<T>> {
+        Ok(PendingChange {
+            lsn,
+            relation: self.relation(rel_id)?.clone(),
+            change,
+            tuples,
+        })
+    }
+
+    async fn push(
+        &mut self,
+        transaction: Option<&TransactionInfo>,
+        change: PendingChange<T>,
+    ) -> crate::Result<()> {
+        let transaction = transaction
+            .context("change outside of transaction")
+            .map_err(Error::Protocol)?;
+        self.dispatch(change, transaction).await
+    }
+
+    async fn dispatch(
+        &mut self,
+        mut change: PendingChange<T>,
+        transaction: &TransactionInfo,
+    ) -> crate::Result<()> {
+        if let Some(watermarks) = &mut self.watermarks {
+            let tuples = std::mem::take(&mut change.tuples);
+            watermarks.record(&change.relation, &change.change, tuples);
+        }
+        self.dispatcher
+            .push(change.into_envelope(transaction))
+            .instrument(self.span.clone())
+            .await
+            .map_err(|e| Error::or(e, Error::Handler))
+    }
+
+    /// Copies the rows of a change if incremental snapshots are enabled,
+    /// since they may have to be compared with the rows of a chunk.
+    fn copy_tuples<const N: usize>(
+        &self,
+        rel_id: Oid,
+        tuples: [Option<&Tuple>; N],
+    ) -> crate::Result<Vec<Vec<TupleData>>> {
+        if self.watermarks.is_none() {
+            return Ok(vec![]);
+        }
+        let relation = self.relation(rel_id)?;
+        tuples
+            .into_iter()
+            .flatten()
+            .map(|tuple| Ok(Row::new(relation, tuple, self.format)?.copy_data()))
+            .collect::<anyhow::Result<_>>()
+            .map_err(Error::Protocol)
+    }
+
+    fn is_watermark(&self, rel_id: Oid) -> bool {
+        match (&self.watermarks, self.relations.get(&rel_id)) {
+            (Some(watermarks), Some(relation)) => watermarks.is_watermark(relation),
+            _ => false,
+        }
+    }
+
+    /// Hands the rows of a chunk to the dispatcher once its high watermark
+    /// shows up, within the watermark's transaction.
+    async fn on_watermark(
+        &mut self,
+        rel_id: Oid,
+        tuple: &Tuple,
+        lsn: PgLsn,
+        transaction: Option<&TransactionInfo>,
+    ) -> crate::Result<()> {
+        let relation = self.relation(rel_id)?.clone();
+        let row = Row::new(&relation, tuple, self.format).map_err(Error::Protocol)?;
+        let Some(watermarks) = &mut self.watermarks else {
+            return Ok(());
+        };
+        let Some(chunk) = watermarks
+            .on_watermark(&row)
+            .await
+            .map_err(|e| Error::decode(relation.name(), lsn, e))?
+        else {
+            return Ok(());
+        };
+
+        let transaction = transaction
+            .context("change outside of transaction")
+            .map_err(Error::Protocol)?;
+        for data in &chunk.rows {
+            let entity = Row::from_data(&chunk.relation, data, Format::Text)
+                .and_then(|row| T::from_row(&row))
+                .map_err(|e| Error::decode(chunk.relation.name(), lsn, e))?;
+            let change = Envelope {
+                change: ChangeEvent::Snapshot(entity),
+                metadata: Metadata {
+                    lsn,
+                    xid: transaction.xid,
+                    commit_lsn: transaction.commit_lsn,
+                    commit_time: transaction.commit_time,
+                    relation: chunk.relation.clone(),
+                    origin: None,
+                },
+            };
+            self.dispatcher
+                .push(change)
+                .await
+                .map_err(|e| Error::or(e, Error::Handler))?;
+        }
+        // The handle may have stopped waiting
+        let _ = chunk.done.send(());
+        Ok(())
+    }
+
+    /// The new row of an UPDATE, with its unchanged TOAST values
+    /// filled in according to [`ReplicationConfig::unchanged_toast`].
+    ///
+    /// Takes `&mut self` so that the future does not require `Self: Sync`.
+    async fn new_data(
+        &mut self,
+        rel_id: Oid,
+        old_tuple: Option<&Tuple>,
+        new_tuple: &Tuple,
+    ) -> crate::Result<Vec<TupleData>> {
+        let relation = self.relation(rel_id)?;
+        let mut data = Row::new(relation, new_tuple, self.format)
+            .map_err(Error::Protocol)?
+            .copy_data();
+        let unchanged = data
+            .iter()
+            .enumerate()
+            .filter(|(_, data)| matches!(data, TupleData::UnchangedToast))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let unchanged_toast = self.replication_config.unchanged_toast.unwrap_or_default();
+        if unchanged.is_empty() || unchanged_toast == UnchangedToast::Keep {
+            return Ok(data);
+        }
+
+        if let Some(old_tuple) = old_tuple {
+            let old_data = Row::new(relation, old_tuple, self.format)
+                .map_err(Error::Protocol)?
+                .copy_data();
+            for &i in &unchanged {
+                data[i] = copy_tuple_data(&old_data[i]);
+            }
+        } else if unchanged_toast == UnchangedToast::Lookup {
+            let client = self
+                .lookup_client
+                .as_ref()
+                .context("looking up unchanged TOAST values requires a lookup client")
+                .map_err(Error::Setup)?;
+            let row = Row::from_data(relation, &data, self.format).map_err(Error::Protocol)?;
+            let values = client
+                .lookup_columns(&row, &unchanged)
+                .await
+                .with_context(|| format!("table {}: unchanged TOAST lookup", relation.name()))
+                .map_err(Error::Connection)?;
+            // Deleted since, keep them unchanged
+            if let Some(values) = values {
+                for (i, value) in unchanged.into_iter().zip(values) {
+                    data[i] = value;
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn relation(&self, rel_id: Oid) -> crate::Result<&Arc<Relation>> {
+        self.relations
+            .get(&rel_id)
+            .with_context(|| format!("received change for unknown relation {rel_id}"))
+            .map_err(Error::Protocol)
+    }
+
+    fn decode(&self, rel_id: Oid, tuple: &Tuple, lsn: PgLsn) -> crate::Result<T> {
+        let relation = self.relation(rel_id)?;
+        Row::new(relation, tuple, self.format)
+            .and_then(|row| T::from_row(&row))
+            .map_err(|e| Error::decode(relation.name(), lsn, e))
+    }
+
+    fn decode_data(&self, rel_id: Oid, data: &[TupleData], lsn: PgLsn) -> crate::Result<T> {
+        let relation = self.relation(rel_id)?;
+        Row::from_data(relation, data, self.format)
+            .and_then(|row| T::from_row(&row))
+            .map_err(|e| Error::decode(relation.name(), lsn, e))
+    }
+
+    fn decode_old(
+        &self,
+        rel_id: Oid,
+        old_tuple: Option<&Tuple>,
+        key_tuple: Option<&Tuple>,
+        lsn: PgLsn,
+    ) -> crate::Result<Option<OldRow<T>>> {
+        match (old_tuple, key_tuple) {
+            (Some(tuple), _) => Ok(Some(OldRow::Full(self.decode(rel_id, tuple, lsn)?))),
+            (None, Some(tuple)) => Ok(Some(OldRow::Key(self.decode(rel_id, tuple, lsn)?))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Finishes processing all the changes of a transaction,
+    /// then ACKs the whole transaction.
+    async fn commit(&mut self, transaction: TransactionInfo) -> crate::Result<()> {
+        let end_lsn = transaction.end_lsn;
+        let span = std::mem::replace(&mut self.span, tracing::Span::none());
+        self.dispatcher
+            .commit(transaction)
+            .instrument(span.clone())
+            .await
+            .map_err(|e| Error::or(e, Error::Handler))?;
+        self.metrics.record_transaction();
+        self.ack(end_lsn).instrument(span).await
+    }
+
+    async fn ack(&mut self, lsn: PgLsn) -> crate::Result<()> {
+        self.lsn = lsn;
+        self.send_status(true)
+            .await
+            .map_err(|source| Error::Ack { lsn, source })?;
+        tracing::debug!(%lsn, "acked");
+        Ok(())
+    }
+
+    /// Lets in-flight handlers finish without ACKing their uncommitted
+    /// transaction, then closes the stream.
+    async fn close(&mut self) -> crate::Result<()> {
+        self.dispatcher
+            .close()
+            .await
+            .map_err(|e| Error::or(e, Error::Handler))?;
+        self.send_status(false).await.map_err(Error::Connection)?;
+        self.stream
+            .as_mut()
+            .close()
+            .await
+            .map_err(|e| Error::Connection(e.into()))?;
+        tracing::info!(lsn = %self.lsn, "shut down");
+        Ok(())
+    }
+
+    async fn send_status(&mut self, reply: bool) -> anyhow::Result<()> {
+        self.metrics.record_acked_lsn(self.lsn);
+        let ssu = prepare_ssu(self.lsn, reply);
         self.stream.as_mut().send(ssu).await?;
-        println!("- ACKED");
         Ok(())
     }
 }
 
+/// A change whose transaction has not committed yet.
+struct PendingChange<T> {
+    lsn: PgLsn,
+    relation: Arc<Relation>,
+    change: ChangeEvent<T>,
+    /// Old and new rows, see [`Subscriber::copy_tuples`]
+    tuples: Vec<Vec<TupleData>>,
+}
+
+impl<T> PendingChange<T> {
+    fn into_envelope(self, transaction: &TransactionInfo) -> Envelope<T> {
+        Envelope {
+            change: self.change,
+            metadata: Metadata {
+                lsn: self.lsn,
+                xid: transaction.xid,
+                commit_lsn: transaction.commit_lsn,
+                commit_time: transaction.commit_time,
+                relation: self.relation,
+                origin: transaction.origin.clone(),
+            },
+        }
+    }
+}
+
+/// Periodically checks the slot against the [`WalSafeguard`], returning it
+/// once it is to be recreated. Failed checks are only logged.
+async fn check_wal(
+    safeguard: WalSafeguard,
+    client: Arc<db::DbClient>,
+    slot: String,
+) -> db::ReplicationSlot {
+    let mut interval = tokio::time::interval(safeguard.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match client.replication_slot(&slot).await {
+            Ok(Some(slot)) if safeguard.check(&slot) == Some(WalAction::Recreate) => {
+                return slot;
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(error = e.report(), "could not check retained WAL"),
+        }
+    }
+}
+
+/// Drops a slot whose stream has just been closed, which
+/// the server takes a moment to notice.
+async fn drop_inactive_slot(client: &db::DbClient, slot: &str) -> crate::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match client.drop_replication_slot(slot).await {
+            Ok(()) => {
+                tracing::warn!(slot, "dropped replication slot");
+                return Ok(());
+            }
+            Err(_) if attempt < 50 => {
+                attempt += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn start_replication(
+    client: &db::DbClient<true>,
+    replication_config: &ReplicationConfig,
+    lsn: PgLsn,
+) -> anyhow::Result<tokio_postgres::CopyBothDuplex<Bytes>> {
+    let mut options = vec![format!(
+        r#""publication_names" '{}'"#,
+        replication_config.publication
+    )];
+    if replication_config.streaming {
+        options.push(r#""proto_version" '2'"#.into());
+        options.push(r#""streaming" 'on'"#.into());
+    } else {
+        options.push(r#""proto_version" '1'"#.into());
+    }
+    if replication_config.binary {
+        options.push(r#""binary" 'true'"#.into());
+    }
+    if replication_config.origin == OriginFilter::Local {
+        options.push(r#""origin" 'none'"#.into());
+    }
+
+    let stream = client
+        .copy_both_simple::<bytes::Bytes>(&format!(
+            r#"
+            START_REPLICATION SLOT {slot}
+            LOGICAL {lsn}
+            ({options});
+            "#,
+            slot = replication_config.replication_slot,
+            options = options.join(", "),
+        ))
+        .await?;
+
+    Ok(stream)
+}
+
+fn is_sql_state(error: &anyhow::Error, state: &SqlState) -> bool {
+    error
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(tokio_postgres::Error::code)
+        == Some(state)
+}
+
 async fn get_start_lsn(
     client: &db::DbClient<true>,
     replication_config: &ReplicationConfig,
@@ -141,8 +948,27 @@ async fn get_start_lsn(
     Ok(lsn)
 }
 
+fn transaction_span(transaction: &TransactionInfo) -> tracing::Span {
+    tracing::debug_span!(
+        "transaction",
+        xid = transaction.xid,
+        commit_lsn = %transaction.commit_lsn,
+        origin = transaction.origin.as_deref(),
+    )
+}
+
+/// Converts a timestamp sent by Postgres, in microseconds since 2000-01-01.
+fn pg_timestamp(micros: i64) -> anyhow::Result<DateTime<Utc>> {
+    const MICROS_FROM_UNIX_EPOCH_TO_2000: i64 = 946_684_800_000_000;
+
+    micros
+        .checked_add(MICROS_FROM_UNIX_EPOCH_TO_2000)
+        .and_then(DateTime::from_timestamp_micros)
+        .context("invalid timestamp")
+}
+
 // https://github.com/tablelandnetwork/pglogrepl-rust/blob/5fb7b8d55d07246077898489c18361d71c835b7b/src/replication.rs#L109
-fn prepare_ssu(write_lsn: PgLsn) -> Bytes {
+fn prepare_ssu(write_lsn: PgLsn, reply: bool) -> Bytes {
     const SECONDS_FROM_UNIX_EPOCH_TO_2000: u128 = 946684800;
 
     let write_lsn_bytes = u64::from(write_lsn).to_be_bytes();
@@ -172,7 +998,7 @@ fn prepare_ssu(write_lsn: PgLsn) -> Bytes {
     //0, 0, 0, 0, 0, 0, 0, 0,
     data_to_send.extend_from_slice(&time_since_2000.to_be_bytes());
     // Byte1; If 1, the client requests the server to reply to this message immediately. This can be used to ping the server, to test if the connection is still healthy.
-    data_to_send.extend_from_slice(&[1]);
+    data_to_send.extend_from_slice(&[reply as u8]);
 
     Bytes::from(data_to_send)
 }
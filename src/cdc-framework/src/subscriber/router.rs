@@ -0,0 +1,190 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use futures::future::BoxFuture;
+use postgres_replication::protocol::TupleData;
+
+use super::{
+    dispatch::{Dispatcher, EventDispatch},
+    event::{Envelope, TransactionInfo},
+    handler::EventHandler,
+};
+use crate::{
+    db::{Dispatch, Entity, Format, Relation, Row},
+    metrics::Metrics,
+    Error,
+};
+
+/// A row of any replicated table, decoded by the [`Router`]
+/// into the entity of its table's route.
+pub struct Routed {
+    data: Vec<TupleData>,
+    format: Format,
+}
+
+impl Entity for Routed {
+    /// Unused, the rows of all tables are routed.
+    const TABLE: &'static str = "*";
+
+    fn from_row(row: &Row<'_>) -> anyhow::Result<Self> {
+        Ok(Self {
+            data: row.copy_data(),
+            format: row.format(),
+        })
+    }
+}
+
+/// Routes changes to a handler per table, so that a single publication
+/// and replication slot can serve several tables.
+///
+/// Changes to tables without a route are skipped.
+#[derive(Default)]
+pub struct Router {
+    routes: HashMap<String, Box<dyn Route>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes the changes to [`Entity::TABLE`] to a handler.
+    pub fn route<T, H>(self, handler: H) -> Self
+    where
+        T: Entity,
+        H: EventHandler<T> + Send + Sync + 'static,
+    {
+        self.route_table::<T, H>(T::TABLE, handler)
+    }
+
+    /// Routes the changes to a table, optionally qualified
+    /// by its schema (e.g. `public.items`), to a handler.
+    pub fn route_table<T, H>(mut self, table: impl Into<String>, handler: H) -> Self
+    where
+        T: Entity,
+        H: EventHandler<T> + Send + Sync + 'static,
+    {
+        let route = EventRoute::<T, H> {
+            dispatcher: EventDispatch::new(Arc::new(handler), Dispatch::default()),
+            t: PhantomData,
+        };
+        self.routes.insert(table.into(), Box::new(route));
+        self
+    }
+
+    pub(crate) fn set_dispatch(&mut self, dispatch: Dispatch) {
+        for route in self.routes.values_mut() {
+            route.set_dispatch(dispatch);
+        }
+    }
+
+    fn route_of(&mut self, relation: &Relation) -> Option<&mut Box<dyn Route>> {
+        let qualified = format!("{}.{}", relation.namespace(), relation.name());
+        if self.routes.contains_key(&qualified) {
+            return self.routes.get_mut(&qualified);
+        }
+        self.routes.get_mut(relation.name())
+    }
+}
+
+/// Clones share the handlers, but not the handlers in flight.
+impl Clone for Router {
+    fn clone(&self) -> Self {
+        let routes = self
+            .routes
+            .iter()
+            .map(|(table, route)| (table.clone(), route.boxed_clone()))
+            .collect();
+        Self { routes }
+    }
+}
+
+impl Dispatcher<Routed> for Router {
+    async fn push(&mut self, change: Envelope<Routed>) -> anyhow::Result<()> {
+        let relation = change.metadata.relation.clone();
+        match self.route_of(&relation) {
+            Some(route) => route.push(change).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn commit(&mut self, transaction: TransactionInfo) -> anyhow::Result<()> {
+        for route in self.routes.values_mut() {
+            route.commit(transaction.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        for route in self.routes.values_mut() {
+            route.close().await?;
+        }
+        Ok(())
+    }
+
+    fn set_metrics(&mut self, metrics: Metrics) {
+        for route in self.routes.values_mut() {
+            route.set_metrics(metrics.clone());
+        }
+    }
+}
+
+/// A [`Dispatcher`] for the entity of one table, with the entity type erased.
+trait Route: Send {
+    fn push(&mut self, change: Envelope<Routed>) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn commit(&mut self, transaction: TransactionInfo) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn close(&mut self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn set_dispatch(&mut self, dispatch: Dispatch);
+
+    fn set_metrics(&mut self, metrics: Metrics);
+
+    fn boxed_clone(&self) -> Box<dyn Route>;
+}
+
+struct EventRoute<T, H> {
+    dispatcher: EventDispatch<H>,
+    t: PhantomData<fn() -> T>,
+}
+
+impl<T, H> Route for EventRoute<T, H>
+where
+    T: Entity,
+    H: EventHandler<T> + Send + Sync + 'static,
+{
+    fn push(&mut self, change: Envelope<Routed>) -> BoxFuture<'_, anyhow::Result<()>> {
+        let Envelope { change, metadata } = change;
+        let relation = &metadata.relation;
+        let change = change
+            .try_map(|row| T::from_row(&Row::from_data(relation, &row.data, row.format)?))
+            .map_err(|e| Error::decode(relation.name(), metadata.lsn, e));
+        Box::pin(async move {
+            let change = change?;
+            Dispatcher::<T>::push(&mut self.dispatcher, Envelope { change, metadata }).await
+        })
+    }
+
+    fn commit(&mut self, transaction: TransactionInfo) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(Dispatcher::<T>::commit(&mut self.dispatcher, transaction))
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(Dispatcher::<T>::close(&mut self.dispatcher))
+    }
+
+    fn set_dispatch(&mut self, dispatch: Dispatch) {
+        self.dispatcher.set_dispatch(dispatch);
+    }
+
+    fn set_metrics(&mut self, metrics: Metrics) {
+        Dispatcher::<T>::set_metrics(&mut self.dispatcher, metrics);
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Route> {
+        Box::new(Self {
+            dispatcher: self.dispatcher.clone(),
+            t: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,118 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::db::{ReplicationSlot, WalStatus};
+
+type Policy = Arc<dyn Fn(&WalCheck<'_>) -> WalAction + Send + Sync>;
+
+/// Periodically checks the WAL retained for the replication slot, which
+/// Postgres keeps until the subscriber ACKs it, so that a stuck subscriber
+/// does not fill up the disk of the server.
+///
+/// Exceeding a threshold is logged, and handed to the policy, which may
+/// e.g. alert, pause producers, or have the slot recreated.
+///
+/// Checked by a task of its own, also while handlers are stuck, which are
+/// not waited for when the slot is recreated.
+#[derive(Clone)]
+pub struct WalSafeguard {
+    pub(crate) interval: Duration,
+    warn_bytes: u64,
+    critical_bytes: u64,
+    policy: Policy,
+}
+
+impl WalSafeguard {
+    pub fn new(warn_bytes: u64, critical_bytes: u64) -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            warn_bytes,
+            critical_bytes,
+            policy: Arc::new(|_| WalAction::Continue),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Called on each check exceeding a threshold, only logging by default.
+    pub fn with_policy(
+        mut self,
+        f: impl Fn(&WalCheck<'_>) -> WalAction + Send + Sync + 'static,
+    ) -> Self {
+        self.policy = Arc::new(f);
+        self
+    }
+
+    /// What to do about the slot, `None` if no threshold is exceeded.
+    pub(crate) fn check(&self, slot: &ReplicationSlot) -> Option<WalAction> {
+        let retained_bytes = slot.retained_wal_bytes.unwrap_or(0);
+        let level = if retained_bytes >= self.critical_bytes
+            || matches!(
+                slot.wal_status,
+                Some(WalStatus::Unreserved | WalStatus::Lost)
+            ) {
+            WalLevel::Critical
+        } else if retained_bytes >= self.warn_bytes || slot.wal_status == Some(WalStatus::Extended)
+        {
+            WalLevel::Warn
+        } else {
+            return None;
+        };
+        match level {
+            WalLevel::Warn => tracing::warn!(
+                slot = slot.name,
+                retained_bytes,
+                wal_status = ?slot.wal_status,
+                "replication slot retains a lot of WAL"
+            ),
+            WalLevel::Critical => tracing::error!(
+                slot = slot.name,
+                retained_bytes,
+                wal_status = ?slot.wal_status,
+                "replication slot retains too much WAL"
+            ),
+        }
+        Some((self.policy)(&WalCheck { slot, level }))
+    }
+}
+
+impl fmt::Debug for WalSafeguard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WalSafeguard")
+            .field("interval", &self.interval)
+            .field("warn_bytes", &self.warn_bytes)
+            .field("critical_bytes", &self.critical_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Passed to the [`WalSafeguard`] policy.
+#[derive(Debug)]
+pub struct WalCheck<'a> {
+    pub slot: &'a ReplicationSlot,
+    pub level: WalLevel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WalLevel {
+    /// Above the warning threshold, or retained beyond `max_wal_size`.
+    Warn,
+    /// Above the critical threshold, or no longer retained.
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalAction {
+    Continue,
+    /// Stops the subscriber and drops the slot, failing with
+    /// [`Error::WalRetention`](crate::Error::WalRetention).
+    ///
+    /// The slot is recreated once restarted, skipping the changes retained so
+    /// far, so [`ReplicationConfig::snapshot`] should be set to read the
+    /// tables again.
+    ///
+    /// [`ReplicationConfig::snapshot`]: crate::db::ReplicationConfig::snapshot
+    Recreate,
+}
@@ -0,0 +1,24 @@
+use tokio_util::sync::CancellationToken;
+
+/// Stops a listening subscriber gracefully.
+///
+/// The subscriber stops reading from the replication stream, waits for the
+/// handlers of the current transaction to finish, reports its final position
+/// and closes the stream. Changes of a transaction that was not committed yet
+/// are delivered again on restart.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(CancellationToken);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.cancel();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.is_cancelled()
+    }
+
+    pub(crate) async fn wait(&self) {
+        self.0.cancelled().await
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use tokio_postgres::types::PgLsn;
+
+use super::{
+    dispatch::Dispatcher,
+    event::{ChangeEvent, Envelope, Metadata, TransactionInfo},
+};
+use crate::{
+    db::{DbClient, Entity, Format, ReplicationConfig, Row},
+    Error,
+};
+
+/// Rows handed to the dispatcher at once, before waiting for them to be handled.
+const BATCH_SIZE: usize = 1000;
+const CURSOR: &str = "cdc_snapshot";
+
+/// Creates the replication slot, then hands the existing rows of the published
+/// tables to the dispatcher, as of the position the slot starts streaming from.
+///
+/// The slot is dropped again if the snapshot fails, so that it is retried.
+/// Decode and handler errors are kept as such, other errors are [`Error::Snapshot`]s.
+pub(crate) async fn snapshot<T, D>(
+    client: &DbClient<true>,
+    config: &ReplicationConfig,
+    dispatcher: &mut D,
+) -> crate::Result<()>
+where
+    T: Entity,
+    D: Dispatcher<T>,
+{
+    let lsn = client
+        .begin_snapshot(&config.replication_slot, config.failover)
+        .await
+        .map_err(Error::Snapshot)?;
+    tracing::info!(slot = config.replication_slot, %lsn, "initial snapshot started");
+    match read_tables(client, config, dispatcher, lsn).await {
+        Ok(()) => {
+            client
+                .simple_query("COMMIT;")
+                .await
+                .map_err(|e| Error::Snapshot(e.into()))?;
+            tracing::info!(slot = config.replication_slot, "initial snapshot done");
+            Ok(())
+        }
+        Err(e) => {
+            client
+                .simple_query("ROLLBACK;")
+                .await
+                .map_err(|e| Error::Snapshot(e.into()))?;
+            client
+                .drop_replication_slot(&config.replication_slot)
+                .await
+                .map_err(|e| Error::Snapshot(e.into()))?;
+            Err(Error::or(e, Error::Snapshot))
+        }
+    }
+}
+
+async fn read_tables<T, D>(
+    client: &DbClient<true>,
+    config: &ReplicationConfig,
+    dispatcher: &mut D,
+    lsn: PgLsn,
+) -> anyhow::Result<()>
+where
+    T: Entity,
+    D: Dispatcher<T>,
+{
+    let transaction = TransactionInfo {
+        xid: 0,
+        commit_lsn: lsn,
+        end_lsn: lsn,
+        commit_time: Utc::now(),
+        origin: None,
+    };
+
+    for table in client.snapshot_tables(config).await? {
+        let relation = Arc::new(client.snapshot_relation(&table).await?);
+        client.declare_cursor(CURSOR, &table, &relation).await?;
+        let mut count = 0;
+        loop {
+            let rows = client.fetch(CURSOR, BATCH_SIZE).await?;
+            if rows.is_empty() {
+                break;
+            }
+            count += rows.len();
+            for data in rows {
+                let entity = Row::from_data(&relation, &data, Format::Text)
+                    .and_then(|row| T::from_row(&row))
+                    .map_err(|e| Error::decode(relation.name(), lsn, e))?;
+                let change = Envelope {
+                    change: ChangeEvent::Snapshot(entity),
+                    metadata: Metadata {
+                        lsn,
+                        xid: transaction.xid,
+                        commit_lsn: lsn,
+                        commit_time: transaction.commit_time,
+                        relation: relation.clone(),
+                        origin: None,
+                    },
+                };
+                dispatcher
+                    .push(change)
+                    .await
+                    .map_err(|e| Error::or(e, Error::Handler))?;
+            }
+            dispatcher
+                .commit(transaction.clone())
+                .await
+                .map_err(|e| Error::or(e, Error::Handler))?;
+        }
+        client.close_cursor(CURSOR).await?;
+        tracing::info!(table = relation.name(), rows = count, "table snapshot done");
+    }
+    Ok(())
+}
@@ -0,0 +1,624 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use postgres_replication::protocol::LogicalReplicationMessage;
+use tokio_postgres::types::PgLsn;
+use uuid::Uuid;
+
+use crate::db::Format;
+
+const STREAM_START_TAG: u8 = b'S';
+const STREAM_STOP_TAG: u8 = b'E';
+const STREAM_COMMIT_TAG: u8 = b'c';
+const STREAM_ABORT_TAG: u8 = b'A';
+const INSERT_TAG: u8 = b'I';
+const UPDATE_TAG: u8 = b'U';
+const DELETE_TAG: u8 = b'D';
+const TRUNCATE_TAG: u8 = b'T';
+const ORIGIN_TAG: u8 = b'O';
+/// Messages of streamed transactions which are buffered until they commit
+const CHANGE_TAGS: [u8; 4] = [INSERT_TAG, UPDATE_TAG, DELETE_TAG, TRUNCATE_TAG];
+
+/// `pgoutput` messages, including those added in protocol version 2
+/// for streaming in-progress transactions.
+///
+/// `postgres_replication` only understands protocol version 1,
+/// so the streaming messages are parsed here.
+pub(crate) enum Message {
+    StreamStart {
+        xid: u32,
+    },
+    StreamStop,
+    StreamCommit {
+        xid: u32,
+        commit_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+    },
+    StreamAbort {
+        xid: u32,
+        subxid: u32,
+    },
+    /// A change of a streamed transaction, parsed once it commits.
+    Streamed {
+        subxid: u32,
+        /// Parses as a [`LogicalReplicationMessage`]
+        change: Bytes,
+    },
+    Logical(LogicalReplicationMessage),
+}
+
+impl Message {
+    /// Parses a message, `in_stream` being whether we are between
+    /// a Stream Start and a Stream Stop message.
+    pub(crate) fn parse(data: &Bytes, in_stream: bool, format: Format) -> anyhow::Result<Self> {
+        let mut buf = data.clone();
+        let tag = *buf.first().context("empty message")?;
+        buf.advance(1);
+
+        // https://www.postgresql.org/docs/current/protocol-logicalrep-message-formats.html
+        let msg = match tag {
+            STREAM_START_TAG => {
+                ensure_len(&buf, 5)?;
+                Self::StreamStart { xid: buf.get_u32() }
+            }
+            STREAM_STOP_TAG => Self::StreamStop,
+            STREAM_COMMIT_TAG => {
+                ensure_len(&buf, 29)?;
+                let xid = buf.get_u32();
+                let _flags = buf.get_u8();
+                Self::StreamCommit {
+                    xid,
+                    commit_lsn: buf.get_u64(),
+                    end_lsn: buf.get_u64(),
+                    timestamp: buf.get_i64(),
+                }
+            }
+            STREAM_ABORT_TAG => {
+                ensure_len(&buf, 8)?;
+                Self::StreamAbort {
+                    xid: buf.get_u32(),
+                    subxid: buf.get_u32(),
+                }
+            }
+            _ => {
+                // Changes of a streamed transaction carry the (sub)transaction id
+                // right after the tag, strip it so the message parses as version 1.
+                // Origin messages, sent right after the first Stream Start, do not
+                let (xid, data) = if in_stream && tag != ORIGIN_TAG {
+                    ensure_len(&buf, 4)?;
+                    let xid = buf.get_u32();
+                    let mut data = BytesMut::with_capacity(buf.len() + 1);
+                    data.put_u8(tag);
+                    data.put(buf);
+                    (Some(xid), data.freeze())
+                } else {
+                    (None, data.clone())
+                };
+                let data = match (format, tag) {
+                    (Format::Binary, INSERT_TAG | UPDATE_TAG | DELETE_TAG) => {
+                        retag_binary_tuples(&data)?
+                    }
+                    _ => data,
+                };
+                match xid {
+                    Some(subxid) if CHANGE_TAGS.contains(&tag) => Self::Streamed {
+                        subxid,
+                        change: data,
+                    },
+                    _ => Self::Logical(LogicalReplicationMessage::parse(&data)?),
+                }
+            }
+        };
+        Ok(msg)
+    }
+}
+
+fn ensure_len(buf: &Bytes, len: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(buf.remaining() >= len, "unexpected end of message");
+    Ok(())
+}
+
+/// `postgres_replication` does not support binary tuple data, which has the
+/// same layout as text tuple data. Binary values are tagged as text here and
+/// decoded according to the [`Format`] of the stream later on.
+fn retag_binary_tuples(data: &[u8]) -> anyhow::Result<Bytes> {
+    fn read_bytes<const N: usize>(data: &[u8], pos: usize) -> anyhow::Result<[u8; N]> {
+        let bytes = data
+            .get(pos..pos + N)
+            .context("unexpected end of message")?;
+        Ok(bytes.try_into()?)
+    }
+
+    let mut data = BytesMut::from(data);
+    // Skip the tag and the relation id, which are followed by one or more
+    // tuples, each prefixed by 'K', 'O' or 'N'
+    let mut pos = 5;
+    while pos < data.len() {
+        let columns = u16::from_be_bytes(read_bytes(&data, pos + 1)?);
+        pos += 3;
+        for _ in 0..columns {
+            let tag = *data.get(pos).context("unexpected end of message")?;
+            pos += 1;
+            match tag {
+                b'n' | b'u' => {}
+                b't' | b'b' => {
+                    data[pos - 1] = b't';
+                    pos += 4 + u32::from_be_bytes(read_bytes(&data, pos)?) as usize;
+                }
+                tag => anyhow::bail!("unknown tuple data tag {tag}"),
+            }
+        }
+    }
+    anyhow::ensure!(pos == data.len(), "unexpected end of message");
+    Ok(data.freeze())
+}
+
+/// Changes of a committed streamed transaction, with their LSNs.
+pub(crate) type StreamedChanges = Box<dyn Iterator<Item = anyhow::Result<(PgLsn, Bytes)>> + Send>;
+
+/// Changes of streamed transactions, buffered until they commit.
+///
+/// They are kept as sent, and only decoded once committed. Beyond
+/// [`ReplicationConfig::streaming_memory`], they are spilled to temporary
+/// files, the origins and aborted subtransactions still being kept in memory.
+///
+/// [`ReplicationConfig::streaming_memory`]: crate::db::ReplicationConfig::streaming_memory
+pub(crate) struct StreamedTransactions {
+    current: Option<u32>,
+    transactions: HashMap<u32, StreamedTransaction>,
+    /// Bytes of the changes held in memory
+    buffered: usize,
+    max_buffered: usize,
+}
+
+#[derive(Default)]
+struct StreamedTransaction {
+    origin: Option<Arc<str>>,
+    /// Not spilled yet
+    changes: Vec<StreamedChange>,
+    spilled: Option<SpillFile>,
+    aborted: HashSet<u32>,
+}
+
+struct StreamedChange {
+    subxid: u32,
+    lsn: PgLsn,
+    data: Bytes,
+}
+
+impl StreamedTransactions {
+    pub(crate) fn new(max_buffered: usize) -> Self {
+        Self {
+            current: None,
+            transactions: HashMap::new(),
+            buffered: 0,
+            max_buffered,
+        }
+    }
+
+    pub(crate) fn in_stream(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether there are no uncommitted changes.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub(crate) fn start(&mut self, xid: u32) {
+        self.current = Some(xid);
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.current = None;
+    }
+
+    pub(crate) fn push(&mut self, subxid: u32, lsn: PgLsn, data: Bytes) -> anyhow::Result<()> {
+        let xid = self.current.context("change outside of stream")?;
+        self.buffered += data.len();
+        self.transactions
+            .entry(xid)
+            .or_default()
+            .changes
+            .push(StreamedChange { subxid, lsn, data });
+        if self.buffered > self.max_buffered {
+            for transaction in self.transactions.values_mut() {
+                transaction.spill()?;
+            }
+            self.buffered = 0;
+        }
+        Ok(())
+    }
+
+    /// Sets the replication origin of the current transaction.
+    pub(crate) fn set_origin(&mut self, origin: Arc<str>) -> anyhow::Result<()> {
+        let xid = self.current.context("origin outside of stream")?;
+        self.transactions.entry(xid).or_default().origin = Some(origin);
+        Ok(())
+    }
+
+    /// Discards the changes of an aborted (sub)transaction.
+    pub(crate) fn abort(&mut self, xid: u32, subxid: u32) {
+        let Some(transaction) = self.transactions.get_mut(&xid) else {
+            return;
+        };
+        if xid == subxid {
+            self.buffered -= transaction.buffered();
+            self.transactions.remove(&xid);
+        } else {
+            self.buffered -= transaction.buffered();
+            transaction.changes.retain(|change| change.subxid != subxid);
+            self.buffered += transaction.buffered();
+            transaction.aborted.insert(subxid);
+        }
+    }
+
+    /// The changes of a committed transaction, and its replication origin.
+    pub(crate) fn commit(
+        &mut self,
+        xid: u32,
+    ) -> anyhow::Result<(Option<Arc<str>>, StreamedChanges)> {
+        let transaction = self.transactions.remove(&xid).unwrap_or_default();
+        self.buffered -= transaction.buffered();
+        let spilled = match transaction.spilled {
+            Some(file) => Some(file.read()?),
+            None => None,
+        };
+        let aborted = transaction.aborted;
+        let changes = spilled
+            .into_iter()
+            .flatten()
+            .chain(transaction.changes.into_iter().map(Ok))
+            .filter(move |change| {
+                change
+                    .as_ref()
+                    .map_or(true, |change| !aborted.contains(&change.subxid))
+            })
+            .map(|change| change.map(|change| (change.lsn, change.data)));
+        Ok((transaction.origin, Box::new(changes)))
+    }
+}
+
+impl StreamedTransaction {
+    fn buffered(&self) -> usize {
+        self.changes.iter().map(|change| change.data.len()).sum()
+    }
+
+    /// Appends the changes held in memory to the spill file.
+    fn spill(&mut self) -> anyhow::Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        let file = match &mut self.spilled {
+            Some(file) => file,
+            None => self.spilled.insert(SpillFile::create()?),
+        };
+        for change in self.changes.drain(..) {
+            // Aborted subtransactions are skipped when read
+            file.write(&change)?;
+        }
+        file.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Changes of a transaction spilled to disk, removed once dropped.
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SpillFile {
+    fn create() -> anyhow::Result<Self> {
+        let path = std::env::temp_dir().join(format!("cdc-stream-{}", Uuid::new_v4()));
+        let file = File::create_new(&path)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        tracing::debug!(path = %path.display(), "spilling streamed transaction");
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Each change is written as its subtransaction, LSN, length and data.
+    fn write(&mut self, change: &StreamedChange) -> anyhow::Result<()> {
+        self.writer.write_all(&change.subxid.to_be_bytes())?;
+        self.writer
+            .write_all(&u64::from(change.lsn).to_be_bytes())?;
+        self.writer
+            .write_all(&u32::try_from(change.data.len())?.to_be_bytes())?;
+        self.writer.write_all(&change.data)?;
+        Ok(())
+    }
+
+    fn read(self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<StreamedChange>>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        Ok(std::iter::from_fn(move || {
+            // Keeps the file until read
+            let _file = &self;
+            let mut header = [0; 16];
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+            let mut header = &header[..];
+            let subxid = header.get_u32();
+            let lsn = PgLsn::from(header.get_u64());
+            let mut data = vec![0; header.get_u32() as usize];
+            Some(
+                reader
+                    .read_exact(&mut data)
+                    .map(|()| StreamedChange {
+                        subxid,
+                        lsn,
+                        data: data.into(),
+                    })
+                    .context("could not read spilled change"),
+            )
+        }))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!(path = %self.path.display(), error = %e, "could not remove spill file");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use postgres_replication::protocol::TupleData;
+
+    use super::*;
+
+    const XID: u32 = 42;
+    const SUBXID: u32 = 43;
+    const REL_ID: u32 = 16384;
+
+    /// An INSERT of a row with a NULL, an unchanged TOAST and a value
+    /// tagged `value_tag`, with `xid` after the tag if set.
+    fn insert(xid: Option<u32>, value_tag: u8) -> Bytes {
+        let mut data = BytesMut::new();
+        data.put_u8(INSERT_TAG);
+        if let Some(xid) = xid {
+            data.put_u32(xid);
+        }
+        data.put_u32(REL_ID);
+        data.put_u8(b'N');
+        data.put_u16(3);
+        data.put_u8(b'n');
+        data.put_u8(b'u');
+        data.put_u8(value_tag);
+        data.put_u32(2);
+        data.put_slice(b"42");
+        data.freeze()
+    }
+
+    /// Asserts the values written by [`insert`], tagged as text.
+    fn assert_inserted(msg: &LogicalReplicationMessage) {
+        let LogicalReplicationMessage::Insert(insert) = msg else {
+            panic!("expected an INSERT");
+        };
+        assert_eq!(insert.rel_id(), REL_ID);
+        assert!(matches!(
+            insert.tuple().tuple_data(),
+            [TupleData::Null, TupleData::UnchangedToast, TupleData::Text(value)]
+                if value.as_ref() == b"42"
+        ));
+    }
+
+    fn change(transactions: &mut StreamedTransactions, subxid: u32, lsn: u64) {
+        transactions
+            .push(subxid, lsn.into(), Bytes::from(lsn.to_be_bytes().to_vec()))
+            .unwrap();
+    }
+
+    fn committed(transactions: &mut StreamedTransactions, xid: u32) -> Vec<u64> {
+        let (_, changes) = transactions.commit(xid).unwrap();
+        changes
+            .map(|change| {
+                let (lsn, data) = change.unwrap();
+                assert_eq!(data.as_ref(), u64::from(lsn).to_be_bytes());
+                u64::from(lsn)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stream_messages_are_parsed() {
+        let mut data = BytesMut::new();
+        data.put_u8(STREAM_START_TAG);
+        data.put_u32(XID);
+        data.put_u8(1);
+        let msg = Message::parse(&data.freeze(), false, Format::Text).unwrap();
+        assert!(matches!(msg, Message::StreamStart { xid: XID }));
+
+        let msg = Message::parse(&Bytes::from_static(b"E"), true, Format::Text).unwrap();
+        assert!(matches!(msg, Message::StreamStop));
+
+        let mut data = BytesMut::new();
+        data.put_u8(STREAM_COMMIT_TAG);
+        data.put_u32(XID);
+        data.put_u8(0);
+        data.put_u64(1);
+        data.put_u64(2);
+        data.put_i64(3);
+        let msg = Message::parse(&data.freeze(), false, Format::Text).unwrap();
+        assert!(matches!(
+            msg,
+            Message::StreamCommit {
+                xid: XID,
+                commit_lsn: 1,
+                end_lsn: 2,
+                timestamp: 3,
+            }
+        ));
+
+        let mut data = BytesMut::new();
+        data.put_u8(STREAM_ABORT_TAG);
+        data.put_u32(XID);
+        data.put_u32(SUBXID);
+        let msg = Message::parse(&data.freeze(), false, Format::Text).unwrap();
+        assert!(matches!(
+            msg,
+            Message::StreamAbort {
+                xid: XID,
+                subxid: SUBXID,
+            }
+        ));
+    }
+
+    #[test]
+    fn truncated_messages_fail() {
+        let mut data = BytesMut::new();
+        data.put_u8(STREAM_ABORT_TAG);
+        data.put_u32(XID);
+        assert!(Message::parse(&data.freeze(), false, Format::Text).is_err());
+        assert!(Message::parse(&Bytes::new(), false, Format::Text).is_err());
+        assert!(Message::parse(&Bytes::from_static(b"I\0\0"), true, Format::Text).is_err());
+    }
+
+    #[test]
+    fn changes_of_streamed_transactions_are_kept_as_version_1() {
+        let msg = Message::parse(&insert(Some(SUBXID), b't'), true, Format::Text).unwrap();
+        let Message::Streamed { subxid, change } = msg else {
+            panic!("expected a streamed change");
+        };
+        assert_eq!(subxid, SUBXID);
+        assert_eq!(change, insert(None, b't'));
+        assert_inserted(&LogicalReplicationMessage::parse(&change).unwrap());
+    }
+
+    #[test]
+    fn changes_outside_of_streams_are_parsed() {
+        let msg = Message::parse(&insert(None, b't'), false, Format::Text).unwrap();
+        let Message::Logical(msg) = msg else {
+            panic!("expected a logical replication message");
+        };
+        assert_inserted(&msg);
+    }
+
+    #[test]
+    fn binary_values_are_tagged_as_text() {
+        let msg = Message::parse(&insert(None, b'b'), false, Format::Binary).unwrap();
+        let Message::Logical(msg) = msg else {
+            panic!("expected a logical replication message");
+        };
+        assert_inserted(&msg);
+
+        let msg = Message::parse(&insert(Some(SUBXID), b'b'), true, Format::Binary).unwrap();
+        let Message::Streamed { change, .. } = msg else {
+            panic!("expected a streamed change");
+        };
+        assert_eq!(change, insert(None, b't'));
+    }
+
+    #[test]
+    fn binary_tuples_of_updates_are_retagged() {
+        // An UPDATE with the old key and the new row
+        let mut data = BytesMut::new();
+        data.put_u8(UPDATE_TAG);
+        data.put_u32(REL_ID);
+        for tuple in [b'K', b'N'] {
+            data.put_u8(tuple);
+            data.put_u16(2);
+            data.put_u8(b'b');
+            data.put_u32(1);
+            data.put_u8(tuple);
+            data.put_u8(b'n');
+        }
+        let retagged = retag_binary_tuples(&data).unwrap();
+        let mut expected = data.clone();
+        expected[8] = b't';
+        expected[18] = b't';
+        assert_eq!(retagged, expected.freeze());
+    }
+
+    #[test]
+    fn malformed_tuples_fail_to_be_retagged() {
+        let data = insert(None, b'x');
+        assert!(retag_binary_tuples(&data).is_err());
+        let data = insert(None, b'b');
+        assert!(retag_binary_tuples(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn aborted_subtransactions_are_discarded() {
+        let mut transactions = StreamedTransactions::new(usize::MAX);
+        transactions.start(XID);
+        transactions.set_origin("origin".into()).unwrap();
+        change(&mut transactions, XID, 1);
+        change(&mut transactions, SUBXID, 2);
+        change(&mut transactions, XID, 3);
+        transactions.stop();
+        transactions.abort(XID, SUBXID);
+
+        let (origin, _) = transactions.commit(XID).unwrap();
+        assert_eq!(origin.as_deref(), Some("origin"));
+        assert!(transactions.is_empty());
+
+        transactions.start(XID);
+        change(&mut transactions, XID, 1);
+        change(&mut transactions, SUBXID, 2);
+        change(&mut transactions, XID, 3);
+        transactions.stop();
+        transactions.abort(XID, SUBXID);
+        assert_eq!(committed(&mut transactions, XID), [1, 3]);
+        assert_eq!(transactions.buffered, 0);
+    }
+
+    #[test]
+    fn aborted_transactions_are_discarded() {
+        let mut transactions = StreamedTransactions::new(usize::MAX);
+        transactions.start(XID);
+        change(&mut transactions, XID, 1);
+        transactions.stop();
+        transactions.abort(XID, XID);
+        assert!(transactions.is_empty());
+        assert_eq!(transactions.buffered, 0);
+        assert_eq!(committed(&mut transactions, XID), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn changes_beyond_the_memory_limit_are_spilled() {
+        let other = XID + 10;
+        let mut transactions = StreamedTransactions::new(16);
+        transactions.start(XID);
+        change(&mut transactions, XID, 1);
+        change(&mut transactions, SUBXID, 2);
+        transactions.stop();
+        transactions.start(other);
+        change(&mut transactions, other, 10);
+        transactions.stop();
+        transactions.start(XID);
+        change(&mut transactions, XID, 3);
+        change(&mut transactions, SUBXID, 4);
+        transactions.stop();
+        // Of which 2 was spilled, 4 not
+        transactions.abort(XID, SUBXID);
+        assert_eq!(transactions.buffered, 8);
+
+        let path = transactions.transactions[&XID]
+            .spilled
+            .as_ref()
+            .unwrap()
+            .path
+            .clone();
+        assert_eq!(committed(&mut transactions, XID), [1, 3]);
+        assert_eq!(transactions.buffered, 0);
+        assert!(!path.exists());
+        assert_eq!(committed(&mut transactions, other), [10]);
+        assert!(transactions.is_empty());
+    }
+}
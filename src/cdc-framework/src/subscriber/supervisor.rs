@@ -0,0 +1,273 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::{
+    dispatch::{Dispatcher, EventDispatch, TransactionDispatch},
+    handler::{EventHandler, TransactionHandler},
+    router::{Routed, Router},
+    safeguard::WalSafeguard,
+    shutdown::ShutdownHandle,
+    watermark::{Chunks, SnapshotHandle},
+    Subscriber,
+};
+use crate::{
+    db::{DbClient, DbConfig, Entity, ReplicationConfig, UnchangedToast},
+    metrics::Metrics,
+    Error,
+};
+
+type RestartHook = Box<dyn Fn(&Restart<'_>) + Send + Sync>;
+
+/// Runs a [`Subscriber`], reconnecting and resuming from the slot's
+/// confirmed position whenever the connection drops or a handler fails.
+pub struct Supervisor<T: Entity, D: Dispatcher<T>> {
+    db_config: DbConfig,
+    replication_config: ReplicationConfig,
+    /// Cloned for each connection
+    dispatcher: D,
+    backoff: Backoff,
+    on_restart: Option<RestartHook>,
+    shutdown: ShutdownHandle,
+    /// Shared by the subscribers, so that snapshots survive restarts between chunks
+    chunks: Chunks,
+    metrics: Metrics,
+    wal_safeguard: Option<WalSafeguard>,
+    t: std::marker::PhantomData<T>,
+}
+
+impl<T, H> Supervisor<T, EventDispatch<H>>
+where
+    T: Entity,
+    H: EventHandler<T> + Send + Sync + 'static,
+{
+    pub fn new(
+        db_config: &DbConfig,
+        replication_config: &ReplicationConfig,
+        message_handler: H,
+    ) -> Self {
+        let dispatcher = EventDispatch::new(Arc::new(message_handler), replication_config.dispatch);
+        Self::with_dispatcher(db_config, replication_config, dispatcher)
+    }
+}
+
+impl<T, H> Supervisor<T, TransactionDispatch<T, H>>
+where
+    T: Entity,
+    H: TransactionHandler<T> + Send + Sync + 'static,
+{
+    /// See [`Subscriber::transactional`].
+    pub fn transactional(
+        db_config: &DbConfig,
+        replication_config: &ReplicationConfig,
+        message_handler: H,
+    ) -> Self {
+        let dispatcher = TransactionDispatch::new(Arc::new(message_handler));
+        Self::with_dispatcher(db_config, replication_config, dispatcher)
+    }
+}
+
+impl Supervisor<Routed, Router> {
+    /// See [`Subscriber::routed`].
+    pub fn routed(
+        db_config: &DbConfig,
+        replication_config: &ReplicationConfig,
+        mut router: Router,
+    ) -> Self {
+        router.set_dispatch(replication_config.dispatch);
+        Self::with_dispatcher(db_config, replication_config, router)
+    }
+}
+
+impl<T, D> Supervisor<T, D>
+where
+    T: Entity,
+    D: Dispatcher<T> + Clone,
+{
+    fn with_dispatcher(
+        db_config: &DbConfig,
+        replication_config: &ReplicationConfig,
+        dispatcher: D,
+    ) -> Self {
+        Self {
+            db_config: db_config.clone(),
+            replication_config: replication_config.clone(),
+            dispatcher,
+            backoff: Backoff::default(),
+            on_restart: None,
+            shutdown: ShutdownHandle::default(),
+            chunks: Chunks::default(),
+            metrics: Metrics::default(),
+            wal_safeguard: None,
+            t: std::marker::PhantomData,
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Called before each restart, e.g. for logging or alerting.
+    pub fn on_restart(mut self, f: impl Fn(&Restart<'_>) + Send + Sync + 'static) -> Self {
+        self.on_restart = Some(Box::new(f));
+        self
+    }
+
+    /// See [`Subscriber::with_metrics`].
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// See [`Subscriber::with_wal_safeguard`], restarting once
+    /// the safeguard recreates the slot.
+    pub fn with_wal_safeguard(mut self, safeguard: WalSafeguard) -> Self {
+        self.wal_safeguard = Some(safeguard);
+        self
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// See [`Subscriber::snapshot_handle`].
+    pub fn snapshot_handle(&self, db_client: DbClient) -> crate::Result<SnapshotHandle> {
+        SnapshotHandle::new(db_client, &self.replication_config, &self.chunks)
+    }
+
+    /// Only returns once shut down, with an error which is not transient,
+    /// see [`Error::is_transient`], or with the last error when the maximum
+    /// number of retries has been exceeded.
+    pub async fn run(&self) -> crate::Result<()> {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = self.listen().await;
+            if self.shutdown.is_shutdown() {
+                return result;
+            }
+            let error = match result {
+                Ok(()) => Error::Connection(anyhow::anyhow!("replication stream closed")),
+                Err(e) => e,
+            };
+            if !error.is_transient() {
+                tracing::error!(error = error.report(), "subscriber failed, giving up");
+                return Err(error);
+            }
+
+            // Only back off further if we failed again shortly after restarting
+            if started.elapsed() > self.backoff.max {
+                attempt = 0;
+            }
+            attempt += 1;
+            if self
+                .backoff
+                .max_retries
+                .is_some_and(|max_retries| attempt > max_retries)
+            {
+                tracing::error!(
+                    error = error.report(),
+                    "exceeded maximum number of restarts, giving up"
+                );
+                return Err(error);
+            }
+
+            self.metrics.record_restart();
+            let delay = self.backoff.delay(attempt);
+            tracing::warn!(
+                attempt,
+                ?delay,
+                error = error.report(),
+                "subscriber stopped, restarting"
+            );
+            if let Some(on_restart) = &self.on_restart {
+                on_restart(&Restart {
+                    attempt,
+                    delay,
+                    error: &error,
+                });
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.shutdown.wait() => return Ok(()),
+            }
+        }
+    }
+
+    async fn listen(&self) -> crate::Result<()> {
+        let db_client = DbClient::<true>::new(&self.db_config).await?;
+        if self.replication_config.leader_election {
+            // Waited for here so that standbys can be shut down,
+            // the subscriber then taking the lock again right away
+            tokio::select! {
+                result = db_client.lock_slot(&self.replication_config.replication_slot) => {
+                    result.map_err(Error::Connection)?;
+                }
+                _ = self.shutdown.wait() => return Ok(()),
+            }
+        }
+        let mut subscriber = Subscriber::with_dispatcher(
+            &db_client,
+            &self.replication_config,
+            self.dispatcher.clone(),
+            self.shutdown.clone(),
+            self.chunks.clone(),
+            self.metrics.clone(),
+        )
+        .await?;
+        if self.replication_config.unchanged_toast == Some(UnchangedToast::Lookup) {
+            subscriber = subscriber.with_lookup_client(DbClient::new(&self.db_config).await?);
+        }
+        if let Some(safeguard) = &self.wal_safeguard {
+            subscriber = subscriber
+                .with_wal_safeguard(safeguard.clone(), DbClient::new(&self.db_config).await?);
+        }
+        subscriber.listen().await
+    }
+}
+
+/// Exponential backoff between restarts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    /// Consecutive restarts before giving up, unlimited if `None`.
+    pub max_retries: Option<u32>,
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(self.multiplier.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2,
+            max_retries: None,
+        }
+    }
+}
+
+/// Passed to the [`Supervisor::on_restart`] hook.
+#[derive(Debug)]
+pub struct Restart<'a> {
+    /// Number of consecutive restarts, starting at 1.
+    pub attempt: u32,
+    /// Time until the subscriber is restarted.
+    pub delay: Duration,
+    /// Why the subscriber stopped.
+    pub error: &'a Error,
+}
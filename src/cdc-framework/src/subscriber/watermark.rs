@@ -0,0 +1,508 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Context;
+use postgres_replication::protocol::TupleData;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_postgres::types::Oid;
+use uuid::Uuid;
+
+use super::event::ChangeEvent;
+use crate::{
+    db::{quote_table, DbClient, Format, Relation, ReplicationConfig, Row, SnapshotTable, Value},
+    Error,
+};
+
+/// Rows of a table, read between a low and a high watermark.
+pub(crate) struct Chunk {
+    low: Uuid,
+    high: Uuid,
+    pub(crate) relation: Arc<Relation>,
+    primary_key: Vec<String>,
+    pub(crate) rows: Vec<Vec<TupleData>>,
+    /// Notifies the [`SnapshotHandle`] once the rows have been handed to the dispatcher
+    pub(crate) done: oneshot::Sender<()>,
+}
+
+/// Channel from the [`SnapshotHandle`]s to the subscriber, kept across reconnects.
+#[derive(Clone)]
+pub(crate) struct Chunks {
+    sender: mpsc::UnboundedSender<Chunk>,
+    receiver: Arc<Mutex<mpsc::UnboundedReceiver<Chunk>>>,
+    /// Only one snapshot at a time across all handles,
+    /// so that watermarks are not interleaved
+    running: Arc<Mutex<()>>,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            running: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+/// Re-emits the current rows of a table without stopping the subscriber,
+/// see [`ReplicationConfig::watermark_table`].
+///
+/// The table is read in chunks, in primary key order. Each chunk is read between
+/// a low and a high watermark written to the watermark table, which show up in
+/// the replication stream. Rows changed between the watermarks are dropped from
+/// the chunk, since the change is more recent, and the remaining rows are handed
+/// to the handler as [`ChangeEvent::Snapshot`]s when the high watermark shows up.
+#[derive(Clone)]
+pub struct SnapshotHandle {
+    client: Arc<DbClient>,
+    config: ReplicationConfig,
+    chunks: mpsc::UnboundedSender<Chunk>,
+    chunk_size: usize,
+    /// See [`Chunks`]
+    running: Arc<Mutex<()>>,
+}
+
+impl SnapshotHandle {
+    pub(crate) fn new(
+        client: DbClient,
+        config: &ReplicationConfig,
+        chunks: &Chunks,
+    ) -> crate::Result<Self> {
+        if config.watermark_table.is_none() {
+            return Err(Error::Setup(anyhow::anyhow!(
+                "incremental snapshots require a watermark table"
+            )));
+        }
+        Ok(Self {
+            client: Arc::new(client),
+            config: config.clone(),
+            chunks: chunks.sender.clone(),
+            chunk_size: 1000,
+            running: chunks.running.clone(),
+        })
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Returns once all rows of the table have been handed to the handler.
+    ///
+    /// Fails if the subscriber restarted in the middle of a chunk, in which
+    /// case the snapshot has to be started again.
+    pub async fn snapshot(&self, table: &str) -> crate::Result<()> {
+        let _running = self.running.lock().await;
+        self.read_chunks(table).await.map_err(Error::Snapshot)
+    }
+
+    async fn read_chunks(&self, table: &str) -> anyhow::Result<()> {
+        let table = SnapshotTable::new(&self.config, table);
+        let relation = Arc::new(self.client.snapshot_relation(&table).await?);
+        let primary_key = self.client.primary_key(&table).await?;
+        let positions = primary_key
+            .iter()
+            .map(|column| {
+                relation
+                    .columns()
+                    .iter()
+                    .position(|c| c.name() == column)
+                    .with_context(|| format!("primary key column {column} is not replicated"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut after: Option<Vec<String>> = None;
+        loop {
+            let low = Uuid::new_v4();
+            self.write_watermark(low).await?;
+            let rows = self
+                .client
+                .select_chunk(
+                    &table,
+                    &relation,
+                    &primary_key,
+                    after.as_deref(),
+                    self.chunk_size,
+                )
+                .await?;
+            let last = rows.len() < self.chunk_size;
+            tracing::debug!(table = relation.name(), rows = rows.len(), "chunk read");
+            if let Some(row) = rows.last() {
+                let key = positions
+                    .iter()
+                    .map(|&i| match &row[i] {
+                        TupleData::Text(x) => Ok(String::from_utf8(x.to_vec())?),
+                        _ => anyhow::bail!("unexpected null in primary key"),
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                after = Some(key);
+            }
+
+            // Sent before writing the high watermark,
+            // so that it is received once the watermark shows up
+            let high = Uuid::new_v4();
+            let (done, processed) = oneshot::channel();
+            self.chunks
+                .send(Chunk {
+                    low,
+                    high,
+                    relation: relation.clone(),
+                    primary_key: primary_key.clone(),
+                    rows,
+                    done,
+                })
+                .map_err(|_| anyhow::anyhow!("subscriber was dropped"))?;
+            self.write_watermark(high).await?;
+            processed
+                .await
+                .context("chunk was not processed, the subscriber may have restarted")?;
+
+            if last {
+                tracing::info!(table = relation.name(), "incremental snapshot done");
+                return Ok(());
+            }
+        }
+    }
+
+    async fn write_watermark(&self, watermark: Uuid) -> anyhow::Result<()> {
+        let table = self
+            .config
+            .watermark_table
+            .as_ref()
+            .context("no watermark table")?;
+        self.client
+            .execute(
+                &format!(
+                    r#"
+                    INSERT INTO {table} (slot, watermark)
+                    VALUES ($1, $2)
+                    ON CONFLICT (slot) DO UPDATE SET watermark = EXCLUDED.watermark;
+                    "#,
+                    table = quote_table(table),
+                ),
+                &[&self.config.replication_slot, &watermark],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Changes handed to the dispatcher since the last low watermark.
+struct Window {
+    low: Uuid,
+    changed: Vec<(Arc<Relation>, Vec<TupleData>)>,
+    truncated: HashSet<Oid>,
+}
+
+/// The subscriber's side of incremental snapshots.
+pub(crate) struct Watermarks {
+    /// Schema and name
+    table: (String, String),
+    slot: String,
+    format: Format,
+    chunks: Chunks,
+    /// Chunks waiting for their high watermark
+    pending: Vec<Chunk>,
+    window: Option<Window>,
+}
+
+impl Watermarks {
+    /// `table` being the schema and name of the watermark table.
+    pub(crate) fn new(
+        table: (String, String),
+        config: &ReplicationConfig,
+        format: Format,
+        chunks: Chunks,
+    ) -> Self {
+        Self {
+            table,
+            slot: config.replication_slot.clone(),
+            format,
+            chunks,
+            pending: vec![],
+            window: None,
+        }
+    }
+
+    pub(crate) fn chunks(&self) -> &Chunks {
+        &self.chunks
+    }
+
+    pub(crate) fn is_watermark(&self, relation: &Relation) -> bool {
+        let (schema, name) = &self.table;
+        relation.namespace() == schema && relation.name() == name
+    }
+
+    /// Keeps track of a change handed to the dispatcher, `tuples` being
+    /// its old and new rows.
+    pub(crate) fn record<T>(
+        &mut self,
+        relation: &Arc<Relation>,
+        change: &ChangeEvent<T>,
+        tuples: Vec<Vec<TupleData>>,
+    ) {
+        let Some(window) = &mut self.window else {
+            return;
+        };
+        if let ChangeEvent::Truncate { .. } = change {
+            window.truncated.insert(relation.id());
+        }
+        window
+            .changed
+            .extend(tuples.into_iter().map(|tuple| (relation.clone(), tuple)));
+    }
+
+    /// Handles a write to the watermark table, returning the chunk to hand
+    /// to the dispatcher if it is a high watermark.
+    ///
+    /// A watermark is a high one if its chunk has been received, otherwise
+    /// it is a low one and opens a new window.
+    pub(crate) async fn on_watermark(&mut self, row: &Row<'_>) -> anyhow::Result<Option<Chunk>> {
+        if row.get::<String>("slot")? != self.slot {
+            return Ok(None);
+        }
+        let watermark: Uuid = row.get("watermark")?;
+
+        {
+            let mut receiver = self.chunks.receiver.lock().await;
+            while let Ok(chunk) = receiver.try_recv() {
+                self.pending.push(chunk);
+            }
+        }
+        self.pending.retain(|chunk| !chunk.done.is_closed());
+
+        let Some(i) = self.pending.iter().position(|c| c.high == watermark) else {
+            self.window = Some(Window {
+                low: watermark,
+                changed: vec![],
+                truncated: HashSet::new(),
+            });
+            return Ok(None);
+        };
+        // Earlier chunks missed their high watermark
+        let mut chunk = self
+            .pending
+            .drain(..=i)
+            .next_back()
+            .context("missing chunk")?;
+        let Some(window) = self.window.take().filter(|w| w.low == chunk.low) else {
+            return Ok(None);
+        };
+
+        if window.truncated.contains(&chunk.relation.id()) {
+            chunk.rows.clear();
+            return Ok(Some(chunk));
+        }
+        let changed = window
+            .changed
+            .iter()
+            .filter(|(relation, _)| relation.id() == chunk.relation.id())
+            .map(|(relation, data)| key(relation, data, self.format, &chunk.primary_key))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut rows = Vec::with_capacity(chunk.rows.len());
+        for row in std::mem::take(&mut chunk.rows) {
+            if !changed.contains(&key(
+                &chunk.relation,
+                &row,
+                Format::Text,
+                &chunk.primary_key,
+            )?) {
+                rows.push(row);
+            }
+        }
+        chunk.rows = rows;
+        Ok(Some(chunk))
+    }
+}
+
+fn key(
+    relation: &Relation,
+    data: &[TupleData],
+    format: Format,
+    primary_key: &[String],
+) -> anyhow::Result<Vec<Value>> {
+    let row = Row::from_data(relation, data, format)?;
+    primary_key.iter().map(|column| row.value(column)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio_postgres::types::Type;
+
+    use super::*;
+    use crate::db::Column;
+
+    const SLOT: &str = "slot";
+
+    fn text(value: impl ToString) -> TupleData {
+        TupleData::Text(Bytes::from(value.to_string()))
+    }
+
+    fn item(id: i32, name: &str) -> Vec<TupleData> {
+        vec![text(id), text(name)]
+    }
+
+    struct Context {
+        watermarks: Watermarks,
+        chunks: Chunks,
+        watermark_relation: Relation,
+        items: Arc<Relation>,
+    }
+
+    impl Context {
+        fn new() -> Self {
+            let config = ReplicationConfig {
+                replication_slot: SLOT.into(),
+                watermark_table: Some("cdc.watermarks".into()),
+                ..Default::default()
+            };
+            let chunks = Chunks::default();
+            let column =
+                |name: &str, type_: Type, is_key| Column::new(name.into(), type_.oid(), is_key);
+            Self {
+                watermarks: Watermarks::new(
+                    ("cdc".into(), "watermarks".into()),
+                    &config,
+                    Format::Text,
+                    chunks.clone(),
+                ),
+                chunks,
+                watermark_relation: Relation::new(
+                    1,
+                    "cdc".into(),
+                    "watermarks".into(),
+                    vec![
+                        column("slot", Type::TEXT, true),
+                        column("watermark", Type::UUID, false),
+                    ],
+                ),
+                items: Arc::new(Relation::new(
+                    2,
+                    "public".into(),
+                    "items".into(),
+                    vec![
+                        column("id", Type::INT4, true),
+                        column("name", Type::TEXT, false),
+                    ],
+                )),
+            }
+        }
+
+        /// Sends a chunk of the rows `1..=3`, as a [`SnapshotHandle`] would.
+        fn send_chunk(&self, low: Uuid, high: Uuid) -> oneshot::Receiver<()> {
+            let (done, processed) = oneshot::channel();
+            self.chunks
+                .sender
+                .send(Chunk {
+                    low,
+                    high,
+                    relation: self.items.clone(),
+                    primary_key: vec!["id".into()],
+                    rows: vec![item(1, "a"), item(2, "b"), item(3, "c")],
+                    done,
+                })
+                .unwrap();
+            processed
+        }
+
+        async fn watermark(&mut self, slot: &str, watermark: Uuid) -> Option<Chunk> {
+            let data = vec![text(slot), text(watermark)];
+            let row = Row::from_data(&self.watermark_relation, &data, Format::Text).unwrap();
+            self.watermarks.on_watermark(&row).await.unwrap()
+        }
+
+        fn record(&mut self, change: ChangeEvent<()>, tuples: Vec<Vec<TupleData>>) {
+            let items = self.items.clone();
+            self.watermarks.record(&items, &change, tuples);
+        }
+    }
+
+    fn ids(chunk: &Chunk) -> Vec<String> {
+        chunk
+            .rows
+            .iter()
+            .map(|row| match &row[0] {
+                TupleData::Text(id) => String::from_utf8(id.to_vec()).unwrap(),
+                _ => panic!("unexpected id"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn watermark_tables_are_matched_by_schema() {
+        let ctx = Context::new();
+        assert!(ctx.watermarks.is_watermark(&ctx.watermark_relation));
+        let same_name = Relation::new(
+            3,
+            "public".into(),
+            "watermarks".into(),
+            ctx.watermark_relation.columns().to_vec(),
+        );
+        assert!(!ctx.watermarks.is_watermark(&same_name));
+    }
+
+    #[tokio::test]
+    async fn rows_changed_between_the_watermarks_are_dropped() {
+        let mut ctx = Context::new();
+        let (low, high) = (Uuid::new_v4(), Uuid::new_v4());
+        // Before the low watermark, so kept
+        ctx.record(ChangeEvent::Insert(()), vec![item(1, "x")]);
+        assert!(ctx.watermark(SLOT, low).await.is_none());
+        let _processed = ctx.send_chunk(low, high);
+        // Old and new row of an update
+        ctx.record(
+            ChangeEvent::Update { old: None, new: () },
+            vec![item(2, "b"), item(2, "x")],
+        );
+        // Another slot's watermark is ignored
+        assert!(ctx.watermark("other", high).await.is_none());
+
+        let chunk = ctx.watermark(SLOT, high).await.unwrap();
+        assert_eq!(ids(&chunk), ["1", "3"]);
+    }
+
+    #[tokio::test]
+    async fn truncated_tables_drop_the_whole_chunk() {
+        let mut ctx = Context::new();
+        let (low, high) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(ctx.watermark(SLOT, low).await.is_none());
+        let _processed = ctx.send_chunk(low, high);
+        ctx.record(
+            ChangeEvent::Truncate {
+                cascade: false,
+                restart_identity: false,
+            },
+            vec![],
+        );
+
+        let chunk = ctx.watermark(SLOT, high).await.unwrap();
+        assert!(chunk.rows.is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunks_without_their_low_watermark_are_skipped() {
+        let mut ctx = Context::new();
+        let (low, high) = (Uuid::new_v4(), Uuid::new_v4());
+        // e.g. written before the subscriber restarted
+        let processed = ctx.send_chunk(low, high);
+        assert!(ctx.watermark(SLOT, high).await.is_none());
+        // Dropping the chunk tells the snapshot handle
+        assert!(processed.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn abandoned_chunks_are_dropped() {
+        let mut ctx = Context::new();
+        let (low, high) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(ctx.watermark(SLOT, low).await.is_none());
+        drop(ctx.send_chunk(low, high));
+
+        // Its high watermark then opens a window instead
+        assert!(ctx.watermark(SLOT, high).await.is_none());
+        let next = Uuid::new_v4();
+        let _processed = ctx.send_chunk(high, next);
+        let chunk = ctx.watermark(SLOT, next).await.unwrap();
+        assert_eq!(ids(&chunk), ["1", "2", "3"]);
+    }
+}
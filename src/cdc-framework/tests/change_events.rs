@@ -0,0 +1,780 @@
+use std::{sync::Arc, time::Duration};
+
+use cdc_framework::{
+    db::{
+        DbClient, Entity, OriginFilter, PublicationTable, PublishOperations, ReplicationConfig,
+        Row, Toast, UnchangedToast,
+    },
+    handler_fn, BoxEventHandler, ChangeEvent, Envelope, Error, EventHandler, OldRow, Router,
+    Subscriber, Supervisor, Transaction, TransactionHandler, WalAction, WalLevel, WalSafeguard,
+};
+use tokio::sync::mpsc;
+use tokio_postgres::{types::PgLsn, SimpleQueryMessage};
+
+mod common;
+
+use common::{config, ChannelHandler, Item, TestContext};
+
+#[derive(Debug)]
+struct Document {
+    name: String,
+    body: Toast<String>,
+}
+
+impl Entity for Document {
+    const TABLE: &'static str = "documents";
+
+    fn from_row(row: &Row<'_>) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: row.get("name")?,
+            body: row.get("body")?,
+        })
+    }
+}
+
+struct DocumentChannelHandler(mpsc::UnboundedSender<ChangeEvent<Document>>);
+
+impl EventHandler<Document> for DocumentChannelHandler {
+    async fn handle(&self, msg: Envelope<Document>) -> anyhow::Result<()> {
+        self.0.send(msg.change)?;
+        Ok(())
+    }
+}
+
+struct OriginChannelHandler(mpsc::UnboundedSender<(Option<Arc<str>>, ChangeEvent<Item>)>);
+
+impl EventHandler<Item> for OriginChannelHandler {
+    async fn handle(&self, msg: Envelope<Item>) -> anyhow::Result<()> {
+        self.0.send((msg.metadata.origin, msg.change))?;
+        Ok(())
+    }
+}
+
+struct TransactionChannelHandler(mpsc::UnboundedSender<Transaction<Item>>);
+
+impl TransactionHandler<Item> for TransactionChannelHandler {
+    async fn handle(&self, transaction: Transaction<Item>) -> anyhow::Result<()> {
+        self.0.send(transaction)?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn all_operations_are_delivered() {
+    let mut ctx = TestContext::new().await;
+    ctx.replication_config.publish = PublishOperations::all();
+    let table = &ctx.table;
+    let mut rx = ctx.subscribe().await;
+
+    // Separate transactions, so that events are handled in order
+    for statement in [
+        format!("INSERT INTO {table} VALUES (1, 'a')"),
+        format!("UPDATE {table} SET name = 'b' WHERE id = 1"),
+        format!("DELETE FROM {table} WHERE id = 1"),
+        format!("TRUNCATE {table}"),
+    ] {
+        ctx.execute(&statement).await;
+    }
+
+    let new = |name: &str| Item {
+        id: 1,
+        name: Some(name.into()),
+    };
+    assert!(matches!(rx.recv().await, Some(ChangeEvent::Insert(x)) if x == new("a")));
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Update { old: None, new: x }) if x == new("b")
+    ));
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Delete(OldRow::Key(Item { id: 1, name: None })))
+    ));
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Truncate { .. })
+    ));
+}
+
+#[tokio::test]
+async fn closures_can_be_boxed_handlers() {
+    let ctx = TestContext::new().await;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let handler = BoxEventHandler::new(handler_fn(move |msg: Envelope<Item>| {
+        let sent = tx.send(msg.change);
+        async move { anyhow::Ok(sent?) }
+    }));
+    ctx.listen(handler).await;
+
+    ctx.execute(&format!("INSERT INTO {} VALUES (1, 'a')", ctx.table))
+        .await;
+
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 1, .. }))
+    ));
+}
+
+#[tokio::test]
+async fn rows_which_cannot_be_decoded_fail_with_a_decode_error() {
+    let ctx = TestContext::with_columns("id TEXT PRIMARY KEY, name TEXT").await;
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let bg = ctx.listen(ChannelHandler(tx)).await;
+
+    ctx.execute(&format!("INSERT INTO {} VALUES ('x', 'a')", ctx.table))
+        .await;
+
+    let error = bg.await.unwrap().unwrap_err();
+    assert!(!error.is_transient());
+    assert!(matches!(error, Error::Decode { table, .. } if table == ctx.table));
+}
+
+#[tokio::test]
+async fn supervisor_gives_up_on_errors_which_are_not_transient() {
+    let mut ctx = TestContext::new().await;
+    ctx.replication_config.tables = vec![format!("{}_missing", ctx.table).into()];
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let supervisor = Supervisor::new(&config(), &ctx.replication_config, ChannelHandler(tx))
+        .on_restart(|restart| panic!("restarted after {:?}", restart.error));
+
+    let result = tokio::time::timeout(Duration::from_secs(10), supervisor.run())
+        .await
+        .expect("supervisor kept running");
+    assert!(matches!(result, Err(Error::Setup(_))));
+}
+
+#[tokio::test]
+async fn wal_safeguard_drops_the_slot() {
+    let ctx = TestContext::new().await;
+    let (levels, mut checked) = mpsc::unbounded_channel();
+    let safeguard = WalSafeguard::new(0, 0)
+        .with_interval(Duration::from_millis(100))
+        .with_policy(move |check| {
+            let _ = levels.send(check.level);
+            WalAction::Recreate
+        });
+    let replication_client = ctx.replication_client().await;
+    let mut sub = Subscriber::new(
+        &replication_client,
+        &ctx.replication_config,
+        // Stuck handlers do not keep the slot from being dropped
+        handler_fn(|_: Envelope<Item>| std::future::pending()),
+    )
+    .await
+    .unwrap()
+    .with_wal_safeguard(safeguard, DbClient::new(&config()).await.unwrap());
+    ctx.execute(&format!("INSERT INTO {} VALUES (1, 'a')", ctx.table))
+        .await;
+
+    let slot = &ctx.replication_config.replication_slot;
+    let error = sub.listen().await.unwrap_err();
+    assert!(matches!(error, Error::WalRetention { slot: s, .. } if &s == slot));
+    assert_eq!(checked.recv().await, Some(WalLevel::Critical));
+    assert_eq!(ctx.client.replication_slot(slot).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn standby_takes_over_once_the_leader_disconnects() {
+    let mut ctx = TestContext::new().await;
+    ctx.replication_config.leader_election = true;
+
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let leader_client = ctx.replication_client().await;
+    let leader = Subscriber::new(&leader_client, &ctx.replication_config, ChannelHandler(tx))
+        .await
+        .unwrap();
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let standby_config = ctx.replication_config.clone();
+    let standby = tokio::spawn(async move {
+        let standby_client = DbClient::<true>::new(&config()).await.unwrap();
+        let mut standby = Subscriber::new(&standby_client, &standby_config, ChannelHandler(tx))
+            .await
+            .unwrap();
+        standby.listen().await
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!standby.is_finished());
+
+    drop(leader);
+    drop(leader_client);
+    ctx.execute(&format!("INSERT INTO {} VALUES (1, 'a')", ctx.table))
+        .await;
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 1, .. }))
+    ));
+}
+
+#[tokio::test]
+async fn failover_is_enabled_on_the_slot() {
+    let mut ctx = TestContext::new().await;
+    let slot = ctx.replication_config.replication_slot.clone();
+    let replication_client = ctx.replication_client().await;
+    replication_client
+        .setup(&ctx.replication_config)
+        .await
+        .unwrap();
+    let failover = ctx
+        .client
+        .replication_slot(&slot)
+        .await
+        .unwrap()
+        .unwrap()
+        .failover;
+    assert_eq!(failover, Some(false));
+
+    ctx.replication_config.failover = true;
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let _sub = Subscriber::new(
+        &replication_client,
+        &ctx.replication_config,
+        ChannelHandler(tx),
+    )
+    .await
+    .unwrap();
+    let failover = ctx
+        .client
+        .replication_slot(&slot)
+        .await
+        .unwrap()
+        .unwrap()
+        .failover;
+    assert_eq!(failover, Some(true));
+}
+
+#[tokio::test]
+async fn missing_failover_slot_is_not_created_for_an_existing_publication() {
+    let mut ctx = TestContext::new().await;
+    let slot = ctx.replication_config.replication_slot.clone();
+    ctx.client
+        .setup_publication(&ctx.replication_config)
+        .await
+        .unwrap();
+
+    // As if the slot had not been synchronized before the standby was promoted
+    ctx.replication_config.failover = true;
+    let replication_client = ctx.replication_client().await;
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let result = Subscriber::new(
+        &replication_client,
+        &ctx.replication_config,
+        ChannelHandler(tx),
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::SlotNotSynchronized { slot: ref s }) if *s == slot
+    ));
+    assert!(matches!(
+        replication_client.setup(&ctx.replication_config).await,
+        Err(Error::SlotNotSynchronized { .. })
+    ));
+    assert_eq!(ctx.client.replication_slot(&slot).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn changes_are_filtered_by_origin() {
+    let mut ctx = TestContext::new().await;
+    let table = ctx.table.clone();
+    let (tx, mut any_rx) = mpsc::unbounded_channel();
+    ctx.listen(OriginChannelHandler(tx)).await;
+    ctx.replication_config.replication_slot = format!("{table}_local_slot");
+    ctx.replication_config.origin = OriginFilter::Local;
+    let mut local_rx = ctx.subscribe().await;
+
+    let sink = DbClient::<false>::new(&config()).await.unwrap();
+    sink.setup_replication_origin(&format!("{table}_sink"))
+        .await
+        .unwrap();
+    sink.simple_query(&format!("INSERT INTO {table} VALUES (1, 'sink')"))
+        .await
+        .unwrap();
+    ctx.execute(&format!("INSERT INTO {table} VALUES (2, 'local')"))
+        .await;
+
+    let (origin, change) = any_rx.recv().await.unwrap();
+    assert_eq!(origin.as_deref(), Some(format!("{table}_sink").as_str()));
+    assert!(matches!(change, ChangeEvent::Insert(Item { id: 1, .. })));
+    let (origin, change) = any_rx.recv().await.unwrap();
+    assert_eq!(origin, None);
+    assert!(matches!(change, ChangeEvent::Insert(Item { id: 2, .. })));
+
+    assert!(matches!(
+        local_rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 2, .. }))
+    ));
+}
+
+#[tokio::test]
+async fn transactions_are_delivered_as_a_whole() {
+    let ctx = TestContext::new().await;
+    let table = &ctx.table;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let replication_client = ctx.replication_client().await;
+    let mut sub = Subscriber::transactional(
+        &replication_client,
+        &ctx.replication_config,
+        TransactionChannelHandler(tx),
+    )
+    .await
+    .unwrap();
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    ctx.execute(&format!(
+        "BEGIN;
+        INSERT INTO {table} VALUES (1, 'a'), (2, 'b');
+        UPDATE {table} SET name = 'c' WHERE id = 1;
+        COMMIT;"
+    ))
+    .await;
+    ctx.execute(&format!("INSERT INTO {table} VALUES (3, 'd')"))
+        .await;
+
+    let first = rx.recv().await.unwrap();
+    let second = rx.recv().await.unwrap();
+    assert_eq!(first.changes.len(), 3);
+    assert!(matches!(
+        &first.changes[2].change,
+        ChangeEvent::Update {
+            new: Item { id: 1, .. },
+            ..
+        }
+    ));
+    for (i, envelope) in first.changes.iter().enumerate() {
+        let metadata = &envelope.metadata;
+        assert_eq!(metadata.relation.name(), table);
+        assert_eq!(metadata.xid, first.info.xid);
+        assert_eq!(metadata.commit_time, first.info.commit_time);
+        assert!(metadata.lsn < metadata.commit_lsn);
+        if i > 0 {
+            assert!(metadata.lsn > first.changes[i - 1].metadata.lsn);
+        }
+    }
+    assert_eq!(second.changes.len(), 1);
+    assert!(second.info.xid > first.info.xid);
+    assert!(second.info.commit_lsn >= first.info.end_lsn);
+    assert!(second.info.commit_time >= first.info.commit_time);
+}
+
+#[tokio::test]
+async fn idle_slots_keep_confirming_unrelated_wal() {
+    let mut ctx = TestContext::new().await;
+    ctx.replication_config.status_interval = Duration::from_millis(100);
+    let _rx = ctx.subscribe().await;
+    let other = format!("{}_other", ctx.table);
+    ctx.execute(&format!(r#"CREATE TABLE "{other}" (id INT PRIMARY KEY);"#))
+        .await;
+
+    let slot = &ctx.replication_config.replication_slot;
+    for id in 0..3 {
+        let before: PgLsn = ctx
+            .client
+            .simple_query("SELECT pg_current_wal_lsn()::text AS lsn;")
+            .await
+            .unwrap()
+            .into_iter()
+            .find_map(|msg| match msg {
+                SimpleQueryMessage::Row(row) => row.get("lsn").map(|lsn| lsn.parse().unwrap()),
+                _ => None,
+            })
+            .unwrap();
+        ctx.execute(&format!(r#"INSERT INTO "{other}" VALUES ({id});"#))
+            .await;
+
+        let mut confirmed = None;
+        for _ in 0..100 {
+            let slot = ctx.client.replication_slot(slot).await.unwrap().unwrap();
+            confirmed = slot.confirmed_flush_lsn;
+            if confirmed > Some(before) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(
+            confirmed > Some(before),
+            "confirmed_flush_lsn {confirmed:?} did not advance past {before}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn large_transactions_are_streamed() {
+    let mut ctx = TestContext::new().await;
+    let table = ctx.table.clone();
+    ctx.replication_config.streaming = true;
+    // Spills most of the changes
+    ctx.replication_config.streaming_memory = 16 * 1024;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let replication_client = ctx.replication_client().await;
+    replication_client
+        .simple_query("SET logical_decoding_work_mem = '64kB';")
+        .await
+        .unwrap();
+    let mut sub = Subscriber::transactional(
+        &replication_client,
+        &ctx.replication_config,
+        TransactionChannelHandler(tx),
+    )
+    .await
+    .unwrap();
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    let insert = |from: i32, to: i32| {
+        format!(
+            "INSERT INTO {table} SELECT id, repeat('x', 100) FROM generate_series({from}, {to}) id;"
+        )
+    };
+    ctx.execute(&format!(
+        "BEGIN;
+        {}
+        SAVEPOINT s;
+        {}
+        ROLLBACK TO SAVEPOINT s;
+        {}
+        COMMIT;",
+        insert(1, 1000),
+        insert(1001, 2000),
+        insert(2001, 2001),
+    ))
+    .await;
+    ctx.execute(&format!("BEGIN; {} ROLLBACK;", insert(3001, 5000)))
+        .await;
+    ctx.execute(&insert(6001, 6001)).await;
+
+    let streamed = rx.recv().await.unwrap();
+    let ids = streamed
+        .changes
+        .iter()
+        .map(|envelope| match &envelope.change {
+            ChangeEvent::Insert(item) => item.id,
+            change => panic!("unexpected change {change:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids, (1..=1000).chain([2001]).collect::<Vec<_>>());
+    assert!(streamed
+        .changes
+        .windows(2)
+        .all(|changes| changes[0].metadata.lsn < changes[1].metadata.lsn));
+    let next = rx.recv().await.unwrap();
+    assert!(matches!(
+        next.changes.as_slice(),
+        [Envelope {
+            change: ChangeEvent::Insert(Item { id: 6001, .. }),
+            ..
+        }]
+    ));
+
+    // Both large transactions were streamed rather than sent once decoded
+    let slot = &ctx.replication_config.replication_slot;
+    for _ in 0..50 {
+        let result = ctx
+            .client
+            .simple_query(&format!(
+                "SELECT stream_txns FROM pg_stat_replication_slots WHERE slot_name = '{slot}';"
+            ))
+            .await
+            .unwrap();
+        let stream_txns = result.iter().find_map(|msg| match msg {
+            SimpleQueryMessage::Row(row) => row.get("stream_txns")?.parse::<u64>().ok(),
+            _ => None,
+        });
+        if stream_txns >= Some(2) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("transactions were not streamed");
+}
+
+#[tokio::test]
+async fn changes_are_routed_by_table() {
+    let mut ctx = TestContext::new().await;
+    let items = ctx.table.clone();
+    let others = format!("{items}_others");
+    ctx.execute(&format!(
+        r#"CREATE TABLE "{others}" (id INT PRIMARY KEY, name TEXT);"#
+    ))
+    .await;
+    ctx.replication_config.tables.push(others.clone().into());
+    ctx.replication_config.publish = PublishOperations::all();
+
+    let (items_tx, mut items_rx) = mpsc::unbounded_channel();
+    let (others_tx, mut others_rx) = mpsc::unbounded_channel();
+    let router = Router::new()
+        .route_table::<Item, _>(&items, ChannelHandler(items_tx))
+        .route_table::<Item, _>(&others, ChannelHandler(others_tx));
+    let replication_client = ctx.replication_client().await;
+    let mut sub = Subscriber::routed(&replication_client, &ctx.replication_config, router)
+        .await
+        .unwrap();
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    ctx.execute(&format!(
+        "BEGIN;
+        INSERT INTO {items} VALUES (1, 'a');
+        INSERT INTO {others} VALUES (2, 'b');
+        COMMIT;"
+    ))
+    .await;
+    ctx.execute(&format!("TRUNCATE {items}, {others}")).await;
+
+    assert!(matches!(
+        items_rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 1, .. }))
+    ));
+    assert!(matches!(
+        others_rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 2, .. }))
+    ));
+    for rx in [&mut items_rx, &mut others_rx] {
+        assert!(matches!(
+            rx.recv().await,
+            Some(ChangeEvent::Truncate { .. })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn publication_filters_columns_and_rows() {
+    let mut ctx =
+        TestContext::with_columns("id INT PRIMARY KEY, name TEXT NOT NULL, secret TEXT").await;
+    let table = &ctx.table;
+    ctx.replication_config.tables = vec![PublicationTable::new(table)
+        .columns(["id", "name"])
+        .row_filter("id > 1")];
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let replication_client = ctx.replication_client().await;
+    let mut sub = Subscriber::transactional(
+        &replication_client,
+        &ctx.replication_config,
+        TransactionChannelHandler(tx),
+    )
+    .await
+    .unwrap();
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    for id in 1..=2 {
+        ctx.execute(&format!(
+            "INSERT INTO {table} VALUES ({id}, 'a', 'do not replicate')"
+        ))
+        .await;
+    }
+
+    let transaction = rx.recv().await.unwrap();
+    let [envelope] = transaction.changes.as_slice() else {
+        panic!("expected a single change");
+    };
+    assert!(matches!(
+        envelope.change,
+        ChangeEvent::Insert(Item { id: 2, .. })
+    ));
+    let columns = envelope
+        .metadata
+        .relation
+        .columns()
+        .iter()
+        .map(|column| column.name())
+        .collect::<Vec<_>>();
+    assert_eq!(columns, ["id", "name"]);
+}
+
+#[tokio::test]
+async fn existing_rows_are_delivered_before_changes() {
+    let mut ctx = TestContext::new().await;
+    let table = &ctx.table;
+    ctx.replication_config.snapshot = true;
+    ctx.execute(&format!(
+        r#"INSERT INTO "{table}" VALUES (1, 'a'), (2, 'b');"#
+    ))
+    .await;
+    let mut rx = ctx.subscribe().await;
+
+    ctx.execute(&format!("INSERT INTO {table} VALUES (3, 'c')"))
+        .await;
+
+    let mut snapshot = vec![];
+    for _ in 0..2 {
+        let Some(ChangeEvent::Snapshot(item)) = rx.recv().await else {
+            panic!("expected a snapshot row");
+        };
+        snapshot.push(item.id);
+    }
+    snapshot.sort();
+    assert_eq!(snapshot, [1, 2]);
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 3, .. }))
+    ));
+}
+
+#[tokio::test]
+async fn incremental_snapshot_is_interleaved_with_changes() {
+    let mut ctx = TestContext::new().await;
+    let table = &ctx.table;
+    ctx.replication_config.watermark_table = Some("cdc_watermarks".into());
+    ctx.execute(&format!(
+        r#"INSERT INTO "{table}" SELECT i, 'a' FROM generate_series(1, 5) i;"#
+    ))
+    .await;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let replication_client = ctx.replication_client().await;
+    let mut sub = Subscriber::new(
+        &replication_client,
+        &ctx.replication_config,
+        ChannelHandler(tx),
+    )
+    .await
+    .unwrap();
+    let handle = sub
+        .snapshot_handle(DbClient::<false>::new(&config()).await.unwrap())
+        .unwrap()
+        .with_chunk_size(2);
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    ctx.execute(&format!("UPDATE {table} SET name = 'b' WHERE id = 4"))
+        .await;
+    handle.snapshot(table).await.unwrap();
+
+    let mut snapshot = vec![];
+    let mut updated = false;
+    while snapshot.len() < 5 {
+        match rx.recv().await.unwrap() {
+            ChangeEvent::Snapshot(item) => snapshot.push(item),
+            ChangeEvent::Update { .. } => {
+                assert!(!updated);
+                assert!(snapshot.iter().all(|item| item.id != 4));
+                updated = true;
+            }
+            _ => panic!("unexpected change"),
+        }
+    }
+    assert!(updated);
+    let ids = snapshot.iter().map(|item| item.id).collect::<Vec<_>>();
+    assert_eq!(ids, [1, 2, 3, 4, 5]);
+    assert_eq!(snapshot[3].name.as_deref(), Some("b"));
+}
+
+#[tokio::test]
+async fn watermark_tables_may_be_schema_qualified() {
+    let mut ctx = TestContext::new().await;
+    let table = &ctx.table;
+    let schema = format!("{table}_cdc");
+    ctx.execute(&format!(
+        r#"
+        CREATE SCHEMA "{schema}";
+        INSERT INTO "{table}" SELECT i, 'a' FROM generate_series(1, 3) i;
+        "#
+    ))
+    .await;
+    ctx.replication_config.watermark_table = Some(format!("{schema}.watermarks"));
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let replication_client = ctx.replication_client().await;
+    let mut sub = Subscriber::new(
+        &replication_client,
+        &ctx.replication_config,
+        ChannelHandler(tx),
+    )
+    .await
+    .unwrap();
+    let handle = sub
+        .snapshot_handle(DbClient::<false>::new(&config()).await.unwrap())
+        .unwrap()
+        .with_chunk_size(2);
+    let listening = tokio::spawn(async move { sub.listen().await });
+
+    handle.snapshot(table).await.unwrap();
+    ctx.execute(&format!("INSERT INTO {table} VALUES (4, 'b')"))
+        .await;
+
+    // Watermarks are neither handed to the handler nor fail to decode
+    let mut ids = vec![];
+    for _ in 0..3 {
+        let Some(ChangeEvent::Snapshot(item)) = rx.recv().await else {
+            panic!("expected a snapshot");
+        };
+        ids.push(item.id);
+    }
+    assert_eq!(ids, [1, 2, 3]);
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Insert(Item { id: 4, .. }))
+    ));
+    assert!(!listening.is_finished());
+}
+
+#[tokio::test]
+async fn truncating_the_watermark_table_keeps_other_truncates() {
+    let mut ctx = TestContext::new().await;
+    let table = &ctx.table;
+    let watermarks = format!("{table}_watermarks");
+    ctx.replication_config.publish = PublishOperations::all();
+    ctx.replication_config.watermark_table = Some(watermarks.clone());
+    let mut rx = ctx.subscribe().await;
+
+    ctx.execute(&format!(r#"TRUNCATE "{watermarks}", "{table}";"#))
+        .await;
+    assert!(matches!(
+        rx.recv().await,
+        Some(ChangeEvent::Truncate { .. })
+    ));
+}
+
+#[tokio::test]
+async fn unchanged_toast_values_are_looked_up() {
+    let ctx =
+        TestContext::with_columns("id INT PRIMARY KEY, name TEXT NOT NULL, body TEXT NOT NULL")
+            .await;
+    let table = &ctx.table;
+    ctx.execute(&format!(
+        r#"ALTER TABLE "{table}" ALTER COLUMN body SET STORAGE EXTERNAL;"#
+    ))
+    .await;
+
+    let mut receivers = vec![];
+    for unchanged_toast in [UnchangedToast::Keep, UnchangedToast::Lookup] {
+        let replication_config = ReplicationConfig {
+            publication: format!("{table}_{unchanged_toast:?}_pub").to_lowercase(),
+            replication_slot: format!("{table}_{unchanged_toast:?}_slot").to_lowercase(),
+            unchanged_toast: Some(unchanged_toast),
+            ..ctx.replication_config.clone()
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let replication_client = ctx.replication_client().await;
+        let sub = Subscriber::new(
+            &replication_client,
+            &replication_config,
+            DocumentChannelHandler(tx),
+        )
+        .await
+        .unwrap();
+        let mut sub = sub.with_lookup_client(DbClient::new(&config()).await.unwrap());
+        tokio::spawn(async move { sub.listen().await });
+        receivers.push(rx);
+    }
+
+    let body = "x".repeat(10_000);
+    ctx.execute(&format!(
+        r#"
+        INSERT INTO "{table}" VALUES (1, 'a', '{body}');
+        UPDATE "{table}" SET name = 'b' WHERE id = 1;
+        "#
+    ))
+    .await;
+
+    for (mut rx, expected) in receivers
+        .into_iter()
+        .zip([Toast::Unchanged, Toast::Value(body)])
+    {
+        let Some(ChangeEvent::Insert(_)) = rx.recv().await else {
+            panic!("expected an insert");
+        };
+        let Some(ChangeEvent::Update { new, .. }) = rx.recv().await else {
+            panic!("expected an update");
+        };
+        assert_eq!(new.name, "b");
+        assert_eq!(new.body, expected);
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cdc_framework::{
+    db::{DbClient, DbConfig, Entity, ReplicationConfig, Row},
+    ChangeEvent, Envelope, EventHandler, Subscriber,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+pub fn config() -> DbConfig {
+    DbConfig {
+        host: "localhost".into(),
+        port: 5432,
+        user: "postgres".into(),
+        password: "password".into(),
+        dbname: "postgres".into(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub id: i32,
+    pub name: Option<String>,
+}
+
+impl Entity for Item {
+    const TABLE: &'static str = "items";
+
+    fn from_row(row: &Row<'_>) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+        })
+    }
+}
+
+pub struct ChannelHandler(pub mpsc::UnboundedSender<ChangeEvent<Item>>);
+
+impl EventHandler<Item> for ChannelHandler {
+    async fn handle(&self, msg: Envelope<Item>) -> anyhow::Result<()> {
+        self.0.send(msg.change)?;
+        Ok(())
+    }
+}
+
+pub fn unique_table() -> String {
+    format!(
+        "items_{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    )
+}
+
+/// A new table, with its own publication and replication slot.
+pub struct TestContext {
+    pub table: String,
+    pub replication_config: ReplicationConfig,
+    pub client: DbClient,
+}
+
+impl TestContext {
+    /// A table of [`Item`]s.
+    pub async fn new() -> Self {
+        Self::with_columns("id INT PRIMARY KEY, name TEXT").await
+    }
+
+    pub async fn with_columns(columns: &str) -> Self {
+        let table = unique_table();
+        let replication_config = ReplicationConfig {
+            publication: format!("{table}_pub"),
+            replication_slot: format!("{table}_slot"),
+            tables: vec![table.clone().into()],
+            ..Default::default()
+        };
+        let client = DbClient::new(&config()).await.unwrap();
+        client
+            .simple_query(&format!(r#"CREATE TABLE "{table}" ({columns});"#))
+            .await
+            .unwrap();
+
+        Self {
+            table,
+            replication_config,
+            client,
+        }
+    }
+
+    pub async fn execute(&self, query: &str) {
+        self.client.simple_query(query).await.unwrap();
+    }
+
+    pub async fn replication_client(&self) -> DbClient<true> {
+        DbClient::new(&config()).await.unwrap()
+    }
+
+    /// Spawns a subscriber on its own replication connection.
+    pub async fn listen<H>(&self, handler: H) -> JoinHandle<cdc_framework::Result<()>>
+    where
+        H: EventHandler<Item> + Send + Sync + 'static,
+    {
+        let replication_client = self.replication_client().await;
+        let mut sub = Subscriber::new(&replication_client, &self.replication_config, handler)
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let _replication_client = replication_client;
+            sub.listen().await
+        })
+    }
+
+    /// Spawns a subscriber passing the changes to the returned channel.
+    pub async fn subscribe(&self) -> mpsc::UnboundedReceiver<ChangeEvent<Item>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.listen(ChannelHandler(tx)).await;
+        rx
+    }
+}
@@ -1,4 +1,7 @@
-use cdc_framework::db::{DbClient, DbConfig};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cdc_framework::db::{DbClient, DbConfig, PublicationTable, ReplicationConfig};
+use tokio_postgres::{types::PgLsn, SimpleQueryMessage};
 
 fn config() -> DbConfig {
     DbConfig {
@@ -19,3 +22,143 @@ async fn setup_db_replication_enabled() {
 async fn setup_db_replication_disabled() {
     let _client = DbClient::<false>::new(&config()).await.unwrap();
 }
+
+#[tokio::test]
+async fn replication_slots_are_managed() {
+    let slot = format!(
+        "managed_slot_{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    let client = DbClient::<false>::new(&config()).await.unwrap();
+    let temporary = format!("{slot}_tmp");
+    client
+        .create_temporary_replication_slot(&temporary)
+        .await
+        .unwrap();
+    client
+        .simple_query(&format!(
+            "SELECT pg_create_logical_replication_slot('{slot}', 'pgoutput');"
+        ))
+        .await
+        .unwrap();
+
+    let slots = client.replication_slots().await.unwrap();
+    let created = slots.iter().find(|s| s.name == slot).unwrap();
+    assert_eq!(created.plugin.as_deref(), Some("pgoutput"));
+    assert!(!created.temporary);
+    assert_eq!(created.active_pid, None);
+    assert!(created.retained_wal_bytes.is_some());
+    assert!(slots.iter().any(|s| s.name == temporary && s.temporary));
+
+    // Write some WAL to advance to
+    client
+        .simple_query("SELECT pg_logical_emit_message(false, 'test', 'advance');")
+        .await
+        .unwrap();
+    let current: PgLsn = client
+        .simple_query("SELECT pg_current_wal_lsn()::text AS lsn;")
+        .await
+        .unwrap()
+        .into_iter()
+        .find_map(|msg| match msg {
+            SimpleQueryMessage::Row(row) => row.get("lsn").map(|lsn| lsn.parse().unwrap()),
+            _ => None,
+        })
+        .unwrap();
+    let advanced = client
+        .advance_replication_slot(&slot, current)
+        .await
+        .unwrap();
+    assert!(advanced > created.confirmed_flush_lsn.unwrap());
+    let slot_info = client.replication_slot(&slot).await.unwrap().unwrap();
+    assert_eq!(slot_info.confirmed_flush_lsn, Some(advanced));
+
+    client.drop_replication_slot(&slot).await.unwrap();
+    assert_eq!(client.replication_slot(&slot).await.unwrap(), None);
+
+    // Temporary slots go away with their connection
+    drop(client);
+    let client = DbClient::<false>::new(&config()).await.unwrap();
+    for _ in 0..50 {
+        if client.replication_slot(&temporary).await.unwrap().is_none() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("temporary slot was not dropped");
+}
+
+#[tokio::test]
+async fn publications_are_only_altered_when_they_differ() {
+    let schema = format!(
+        "publication_{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    let client = DbClient::<false>::new(&config()).await.unwrap();
+    client
+        .simple_query(&format!(
+            r#"
+            CREATE SCHEMA "{schema}";
+            CREATE TABLE "{schema}"."items" (id INT PRIMARY KEY, name TEXT, secret TEXT);
+            "#
+        ))
+        .await
+        .unwrap();
+    let mut replication_config = ReplicationConfig {
+        publication: format!("{schema}_pub"),
+        tables: vec![PublicationTable::new(format!("{schema}.items"))
+            .columns(["name", "id"])
+            .row_filter("id > 1")],
+        ..Default::default()
+    };
+    client.setup_publication(&replication_config).await.unwrap();
+
+    // Altering the publication would wait for the lock
+    let locking = DbClient::<false>::new(&config()).await.unwrap();
+    locking
+        .simple_query(&format!(
+            r#"BEGIN; LOCK TABLE "{schema}"."items" IN SHARE UPDATE EXCLUSIVE MODE;"#
+        ))
+        .await
+        .unwrap();
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        client.setup_publication(&replication_config),
+    )
+    .await
+    .expect("unchanged publication was altered")
+    .unwrap();
+    locking.simple_query("ROLLBACK;").await.unwrap();
+
+    replication_config.tables[0].row_filter = Some("id > 2".into());
+    client.setup_publication(&replication_config).await.unwrap();
+    let row_filter = client
+        .simple_query(&format!(
+            "SELECT rowfilter FROM pg_publication_tables WHERE pubname = '{schema}_pub';"
+        ))
+        .await
+        .unwrap()
+        .into_iter()
+        .find_map(|msg| match msg {
+            SimpleQueryMessage::Row(row) => row.get("rowfilter").map(str::to_string),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(row_filter, "(id > 2)");
+
+    // Publications for all tables are left as they are
+    replication_config.publication = format!("{schema}_all");
+    client
+        .simple_query(&format!(
+            r#"CREATE PUBLICATION "{schema}_all" FOR ALL TABLES;"#
+        ))
+        .await
+        .unwrap();
+    client.setup_publication(&replication_config).await.unwrap();
+}
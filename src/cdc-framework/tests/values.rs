@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cdc_framework::{
+    db::{DbClient, DbConfig, Entity, ReplicationConfig, Row, Value},
+    ChangeEvent, Envelope, EventHandler, Subscriber,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+fn config() -> DbConfig {
+    DbConfig {
+        host: "localhost".into(),
+        port: 5432,
+        user: "postgres".into(),
+        password: "password".into(),
+        dbname: "postgres".into(),
+    }
+}
+
+/// All columns of a row, by name.
+struct Values(BTreeMap<String, Value>);
+
+impl Entity for Values {
+    const TABLE: &'static str = "values";
+
+    fn from_row(row: &Row<'_>) -> anyhow::Result<Self> {
+        row.relation()
+            .columns()
+            .iter()
+            .map(|column| Ok((column.name().to_string(), row.value(column.name())?)))
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+struct ChannelHandler(mpsc::UnboundedSender<ChangeEvent<Values>>);
+
+impl EventHandler<Values> for ChannelHandler {
+    async fn handle(&self, msg: Envelope<Values>) -> anyhow::Result<()> {
+        self.0.send(msg.change)?;
+        Ok(())
+    }
+}
+
+/// Creates a table with the columns, and subscribes to its changes.
+async fn subscribe(
+    binary: bool,
+    columns: &str,
+) -> (
+    String,
+    DbClient,
+    mpsc::UnboundedReceiver<ChangeEvent<Values>>,
+) {
+    let table = format!(
+        "values_{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    let replication_config = ReplicationConfig {
+        publication: format!("{table}_pub"),
+        replication_slot: format!("{table}_slot"),
+        tables: vec![table.clone().into()],
+        binary,
+        ..Default::default()
+    };
+
+    let client = DbClient::<false>::new(&config()).await.unwrap();
+    client
+        .simple_query(&format!(r#"CREATE TABLE "{table}" ({columns});"#))
+        .await
+        .unwrap();
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let replication_client = DbClient::<true>::new(&config()).await.unwrap();
+    // Text values are formatted in the time zone of the replication
+    // connection, whose local mean time until 1937 has an offset with seconds
+    replication_client
+        .simple_query("SET TIME ZONE 'Europe/Amsterdam';")
+        .await
+        .unwrap();
+    let mut sub = Subscriber::new(&replication_client, &replication_config, ChannelHandler(tx))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let _replication_client = replication_client;
+        sub.listen().await
+    });
+    (table, client, rx)
+}
+
+async fn decoded_values(binary: bool) -> BTreeMap<String, Value> {
+    let (table, client, mut rx) = subscribe(
+        binary,
+        r#"
+        id UUID PRIMARY KEY,
+        flag BOOL,
+        small INT2,
+        int INT4,
+        big INT8,
+        name TEXT,
+        data BYTEA,
+        created_at TIMESTAMPTZ,
+        payload JSONB,
+        amount NUMERIC,
+        missing TEXT
+        "#,
+    )
+    .await;
+
+    client
+        .simple_query(&format!(
+            r#"
+            INSERT INTO "{table}" VALUES (
+                '67e55044-10b1-426f-9247-bb680e5fe0c8',
+                true,
+                -2,
+                4,
+                8000000000,
+                'name',
+                '\xdeadbeef',
+                '2024-08-01 12:30:00.5+02',
+                '{{"a": [1, 2]}}',
+                -1234.0560,
+                NULL
+            );
+            "#
+        ))
+        .await
+        .unwrap();
+
+    let Some(ChangeEvent::Insert(Values(values))) = rx.recv().await else {
+        panic!("expected insert");
+    };
+    values
+}
+
+async fn decoded_timestamps(binary: bool) -> Vec<Value> {
+    let (table, client, mut rx) = subscribe(binary, "id INT PRIMARY KEY, at TIMESTAMPTZ").await;
+    client
+        .simple_query(&format!(
+            r#"
+            INSERT INTO "{table}" VALUES
+                (1, 'infinity'),
+                (2, '-infinity'),
+                (3, '1900-01-01 00:00:00+00'),
+                (4, '0044-03-15 12:00:00+00 BC');
+            "#
+        ))
+        .await
+        .unwrap();
+
+    let mut timestamps = BTreeMap::new();
+    for _ in 0..4 {
+        let Some(ChangeEvent::Insert(Values(mut values))) = rx.recv().await else {
+            panic!("expected insert");
+        };
+        let Some(Value::Int4(id)) = values.remove("id") else {
+            panic!("expected an id");
+        };
+        timestamps.insert(id, values.remove("at").unwrap());
+    }
+    timestamps.into_values().collect()
+}
+
+#[tokio::test]
+async fn text_and_binary_values_are_decoded() {
+    let expected = BTreeMap::from(
+        [
+            (
+                "id",
+                Value::Uuid(Uuid::from_u128(0x67e5504410b1426f9247bb680e5fe0c8)),
+            ),
+            ("flag", Value::Bool(true)),
+            ("small", Value::Int2(-2)),
+            ("int", Value::Int4(4)),
+            ("big", Value::Int8(8000000000)),
+            ("name", Value::Text("name".into())),
+            ("data", Value::Bytea(vec![0xde, 0xad, 0xbe, 0xef])),
+            (
+                "created_at",
+                Value::TimestampTz(
+                    Utc.with_ymd_and_hms(2024, 8, 1, 10, 30, 0).unwrap()
+                        + chrono::Duration::milliseconds(500),
+                ),
+            ),
+            ("payload", Value::Jsonb(serde_json::json!({"a": [1, 2]}))),
+            ("amount", Value::Numeric("-1234.0560".into())),
+            ("missing", Value::Null),
+        ]
+        .map(|(k, v)| (k.to_string(), v)),
+    );
+
+    assert_eq!(decoded_values(false).await, expected);
+    assert_eq!(decoded_values(true).await, expected);
+}
+
+#[tokio::test]
+async fn text_and_binary_timestamps_are_decoded_alike() {
+    let expected = vec![
+        Value::TimestampTz(DateTime::<Utc>::MAX_UTC),
+        Value::TimestampTz(DateTime::<Utc>::MIN_UTC),
+        Value::TimestampTz(Utc.with_ymd_and_hms(1900, 1, 1, 0, 0, 0).unwrap()),
+        Value::TimestampTz(Utc.with_ymd_and_hms(-43, 3, 15, 12, 0, 0).unwrap()),
+    ];
+
+    assert_eq!(decoded_timestamps(false).await, expected);
+    assert_eq!(decoded_timestamps(true).await, expected);
+}
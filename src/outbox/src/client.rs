@@ -1,8 +1,7 @@
-use anyhow::Context;
 use cdc_framework::db::{self, ReplicationConfig};
 use uuid::Uuid;
 
-use crate::model;
+use crate::{model, Result};
 
 #[derive(Clone)]
 pub struct OutboxClient {
@@ -14,18 +13,19 @@ impl OutboxClient {
     pub async fn new(
         db_config: &db::DbConfig,
         replication_config: &ReplicationConfig,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self> {
         let db_client = db::DbClient::new(db_config).await?;
-        crate::setup(&db_client, &replication_config.table).await?;
+        let table = crate::outbox_table(replication_config)?;
+        crate::setup(&db_client, table).await?;
 
         let db_publisher = cdc_framework::Publisher::new(db_client).await?;
         Ok(Self {
             db_publisher,
-            table: replication_config.table.clone(),
+            table: table.into(),
         })
     }
 
-    pub async fn persist_one(&self, item: impl model::Message) -> anyhow::Result<()> {
+    pub async fn persist_one(&self, item: impl model::Message) -> Result<()> {
         let client = self.db_publisher.as_ref().await;
         let record = item.into_record();
 
@@ -59,7 +59,7 @@ impl OutboxClient {
     pub async fn persist(
         &self,
         items: impl IntoIterator<Item = impl model::Message>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         let mut client_mut = self.db_publisher.as_mut().await;
 
         let transaction = client_mut.transaction().await?;
@@ -94,19 +94,20 @@ impl OutboxClient {
         Ok(())
     }
 
-    pub async fn get_dead_messages(&self) -> anyhow::Result<Vec<Uuid>> {
+    pub async fn get_dead_messages(&self) -> Result<Vec<Uuid>> {
         let client = self.db_publisher.as_ref().await;
         let rows = client
             .query("SELECT * FROM $1 WHERE ttl <= 0", &[&self.table])
             .await?;
 
-        rows.into_iter()
+        let ids = rows
+            .into_iter()
             .map(|row| row.try_get("id"))
-            .collect::<Result<Vec<_>, _>>()
-            .context("Error getting dead messages")
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
     }
 
-    pub(crate) async fn update_ttl(&self, id: Uuid, ttl: i16) -> anyhow::Result<()> {
+    pub(crate) async fn update_ttl(&self, id: Uuid, ttl: i16) -> Result<()> {
         let client = self.db_publisher.as_ref().await;
 
         client
@@ -114,9 +115,7 @@ impl OutboxClient {
                 &format!("UPDATE {} SET ttl = $2 WHERE id = $1;", self.table),
                 &[&id, &(ttl - 1)],
             )
-            .await
-            .context("Error updating TTL")
-            .map(|_| ())?;
+            .await?;
 
         Ok(())
     }
@@ -0,0 +1,25 @@
+/// Errors of the outbox, on top of those of the subscriber.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Cdc(#[from] cdc_framework::Error),
+    /// The replication config does not describe an outbox.
+    #[error("invalid outbox config: {0}")]
+    Config(&'static str),
+    /// A query on the outbox table failed.
+    #[error("outbox query failed")]
+    Database(#[from] tokio_postgres::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// See [`cdc_framework::Error::is_transient`].
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Cdc(e) => e.is_transient(),
+            Self::Config(_) => false,
+            Self::Database(_) => true,
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use cdc_framework::{Envelope, Layer, Metrics};
+use tracing::Instrument;
+
 use crate::{client::OutboxClient, model::EventRecord};
 
 /// Retries handling of messages that failed to be processed.
@@ -11,14 +14,63 @@ use crate::{client::OutboxClient, model::EventRecord};
 pub struct EagerRetryHandler<Inner: cdc_framework::EventHandler<EventRecord>> {
     client: OutboxClient,
     inner: Inner,
+    metrics: Metrics,
 }
 
 impl<Inner> EagerRetryHandler<Inner>
 where
     Inner: cdc_framework::EventHandler<EventRecord>,
 {
-    pub async fn new(client: OutboxClient, inner: Inner) -> anyhow::Result<Self> {
-        Ok(Self { client, inner })
+    pub async fn new(client: OutboxClient, inner: Inner) -> crate::Result<Self> {
+        Ok(Self {
+            client,
+            inner,
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// Records retries, usually into the subscriber's metrics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
+/// Wraps handlers into an [`EagerRetryHandler`], for a
+/// [`HandlerBuilder`](cdc_framework::HandlerBuilder).
+#[derive(Clone)]
+pub struct EagerRetryLayer {
+    client: OutboxClient,
+    metrics: Metrics,
+}
+
+impl EagerRetryLayer {
+    pub fn new(client: OutboxClient) -> Self {
+        Self {
+            client,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// See [`EagerRetryHandler::with_metrics`].
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
+impl<Inner> Layer<Inner> for EagerRetryLayer
+where
+    Inner: cdc_framework::EventHandler<EventRecord>,
+{
+    type Handler = EagerRetryHandler<Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Handler {
+        EagerRetryHandler {
+            client: self.client.clone(),
+            inner,
+            metrics: self.metrics.clone(),
+        }
     }
 }
 
@@ -26,21 +78,36 @@ impl<Inner> cdc_framework::EventHandler<EventRecord> for EagerRetryHandler<Inner
 where
     Inner: cdc_framework::EventHandler<EventRecord> + Send + Sync,
 {
-    async fn handle(&self, msg: EventRecord) -> anyhow::Result<()> {
-        let id = msg.id;
-        let ttl = msg.ttl;
+    async fn handle(&self, msg: Envelope<EventRecord>) -> anyhow::Result<()> {
+        let Some(record) = msg.change.after() else {
+            return self.inner.handle(msg).await;
+        };
+        let id = record.id;
+        let ttl = record.ttl;
+        let span = tracing::info_span!(
+            "outbox_event",
+            %id,
+            agg_id = %record.agg_id,
+            event_type = record.event_type,
+            ttl,
+            lsn = %msg.metadata.lsn,
+        );
 
-        if ttl <= 0 {
-            println!("TTL expired: {:?}", msg.id);
-            return Ok(());
-        }
+        async {
+            if ttl <= 0 {
+                tracing::warn!("TTL expired, dropping event");
+                return Ok(());
+            }
 
-        println!("Handling msg: {id} (TTL {ttl})");
-        if let Err(e) = self.inner.handle(msg).await {
-            println!("Retrying: {:?}", e);
-            self.client.update_ttl(id, ttl).await?;
+            tracing::debug!("handling event");
+            if let Err(e) = self.inner.handle(msg).await {
+                tracing::warn!(error = format!("{e:#}"), "handler failed, retrying");
+                self.metrics.record_retry();
+                self.client.update_ttl(id, ttl).await?;
+            }
+            Ok(())
         }
-
-        Ok(())
+        .instrument(span)
+        .await
     }
 }
@@ -1,17 +1,32 @@
 pub use cdc_framework::{
-    db::{DbClient, DbConfig, ReplicationConfig},
-    EventHandler,
+    db::{DbClient, DbConfig, Dispatch, ReplicationConfig, UnchangedToast},
+    handler_fn, layer,
+    metrics::{self, Metrics},
+    BoxEventHandler, ChangeEvent, Envelope, EventHandler, HandlerBuilder, Layer,
 };
 
 pub mod client;
+mod error;
 pub mod handlers;
 pub mod model;
 pub mod subscriber;
 
+pub use error::{Error, Result};
+
+/// The outbox table, which must be the only table of the publication.
+pub fn outbox_table(replication_config: &ReplicationConfig) -> Result<&str> {
+    match replication_config.tables.as_slice() {
+        [table] if replication_config.schemas.is_empty() => Ok(&table.name),
+        _ => Err(Error::Config(
+            "the outbox publication must contain exactly one table",
+        )),
+    }
+}
+
 pub async fn setup<const REPLICATION: bool>(
     client: &DbClient<REPLICATION>,
     table: &str,
-) -> anyhow::Result<()> {
+) -> Result<()> {
     client
         .simple_query(&format!(
             r#"
@@ -1,9 +1,6 @@
-use core::str;
-use std::{borrow::Cow, str::FromStr};
+use std::hash::Hash;
 
-use anyhow::Context;
-use cdc_framework::db::Entity;
-use postgres_replication::protocol::{Tuple, TupleData};
+use cdc_framework::db::{Entity, Row};
 use uuid::Uuid;
 
 pub trait Message: Sized {
@@ -24,41 +21,27 @@ pub struct EventRecord {
 impl Entity for EventRecord {
     const TABLE: &'static str = "events";
 
-    fn from_tuple(tuple: &Tuple) -> anyhow::Result<Self>
+    fn from_row(row: &Row<'_>) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        Self::try_from(tuple)
+        Self::try_from(row)
+    }
+
+    fn partition_key(&self) -> Option<impl Hash> {
+        Some(self.agg_id)
     }
 }
 
-impl TryFrom<&Tuple> for EventRecord {
+impl TryFrom<&Row<'_>> for EventRecord {
     type Error = anyhow::Error;
 
-    fn try_from(value: &Tuple) -> Result<Self, Self::Error> {
-        fn raw_text(data: &TupleData) -> anyhow::Result<Cow<str>> {
-            match data {
-                TupleData::Text(x) => Ok(String::from_utf8_lossy(x)),
-                _ => anyhow::bail!("expected text"),
-            }
-        }
-
-        let row = value.tuple_data();
-
-        let id = {
-            let x = raw_text(row.first().context("missing id")?)?;
-            Uuid::from_str(&x).unwrap()
-        };
-        let agg_id = {
-            let x = raw_text(row.get(1).context("missing agg_id")?)?;
-            Uuid::from_str(&x).unwrap()
-        };
-        let event_type = raw_text(row.get(2).context("missing event_type")?)?.into();
-        let data = match row.get(3).context("missing col data")? {
-            TupleData::Text(x) => hex::decode(x.slice(2..))?,
-            _ => anyhow::bail!("expected text"),
-        };
-        let ttl = raw_text(row.get(4).context("missing col ttl")?)?.parse()?;
+    fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
+        let id = row.get("id")?;
+        let agg_id = row.get("agg_id")?;
+        let event_type = row.get("event_type")?;
+        let data = row.get("data")?;
+        let ttl = row.get("ttl")?;
 
         Ok(Self {
             id,
@@ -1,16 +1,18 @@
 use cdc_framework::{
-    db::{DbClient, DbConfig, ReplicationConfig},
-    EventHandler,
+    db::{DbClient, DbConfig, ReplicationConfig, UnchangedToast},
+    Backoff, EventDispatch, EventHandler, Metrics, Restart, ShutdownHandle, Supervisor,
+    WalSafeguard,
 };
 use tokio::sync::RwLock;
 
-use crate::{model::EventRecord, setup};
+use crate::{model::EventRecord, outbox_table, setup, Result};
 
 pub struct OutboxSubscriber<H>
 where
-    H: EventHandler<EventRecord>,
+    H: EventHandler<EventRecord> + Send + Sync + 'static,
 {
-    inner: RwLock<cdc_framework::Subscriber<EventRecord, H>>,
+    inner: RwLock<cdc_framework::Subscriber<EventRecord, EventDispatch<H>>>,
+    shutdown: ShutdownHandle,
 }
 
 impl<H> OutboxSubscriber<H>
@@ -21,19 +23,108 @@ where
         db_config: &DbConfig,
         replication_config: &ReplicationConfig,
         handler: H,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self> {
+        let replication_config = &with_toast_lookup(replication_config);
         let replication_client = DbClient::<true>::new(db_config).await?;
-        setup(&replication_client, &replication_config.table).await?;
+        setup(&replication_client, outbox_table(replication_config)?).await?;
 
-        let inner = RwLock::new(
+        let mut inner =
             cdc_framework::Subscriber::new(&replication_client, replication_config, handler)
-                .await?,
-        );
+                .await?;
+        if replication_config.unchanged_toast == Some(UnchangedToast::Lookup) {
+            inner = inner.with_lookup_client(DbClient::new(db_config).await?);
+        }
+        let shutdown = inner.shutdown_handle();
+
+        Ok(Self {
+            inner: RwLock::new(inner),
+            shutdown,
+        })
+    }
+
+    pub fn with_metrics(self, metrics: Metrics) -> Self {
+        Self {
+            inner: RwLock::new(self.inner.into_inner().with_metrics(metrics)),
+            shutdown: self.shutdown,
+        }
+    }
 
-        Ok(Self { inner })
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
-    pub async fn listen(&self) -> anyhow::Result<()> {
-        self.inner.write().await.listen().await
+    pub async fn listen(&self) -> Result<()> {
+        Ok(self.inner.write().await.listen().await?)
     }
 }
+
+/// An [`OutboxSubscriber`] that reconnects and resumes when the
+/// replication connection drops or a handler fails.
+pub struct SupervisedOutboxSubscriber<H>
+where
+    H: EventHandler<EventRecord> + Send + Sync + 'static,
+{
+    inner: Supervisor<EventRecord, EventDispatch<H>>,
+}
+
+impl<H> SupervisedOutboxSubscriber<H>
+where
+    H: EventHandler<EventRecord> + Send + Sync + 'static,
+{
+    pub async fn new(
+        db_config: &DbConfig,
+        replication_config: &ReplicationConfig,
+        handler: H,
+    ) -> Result<Self> {
+        let replication_config = &with_toast_lookup(replication_config);
+        let client = DbClient::<false>::new(db_config).await?;
+        setup(&client, outbox_table(replication_config)?).await?;
+
+        Ok(Self {
+            inner: Supervisor::new(db_config, replication_config, handler),
+        })
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        Self {
+            inner: self.inner.with_backoff(backoff),
+        }
+    }
+
+    pub fn on_restart(self, f: impl Fn(&Restart<'_>) + Send + Sync + 'static) -> Self {
+        Self {
+            inner: self.inner.on_restart(f),
+        }
+    }
+
+    pub fn with_metrics(self, metrics: Metrics) -> Self {
+        Self {
+            inner: self.inner.with_metrics(metrics),
+        }
+    }
+
+    pub fn with_wal_safeguard(self, safeguard: WalSafeguard) -> Self {
+        Self {
+            inner: self.inner.with_wal_safeguard(safeguard),
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.inner.shutdown_handle()
+    }
+
+    pub async fn listen(&self) -> Result<()> {
+        Ok(self.inner.run().await?)
+    }
+}
+
+/// Records are decoded in full, so unchanged `data` payloads are looked up
+/// when their TTL is updated, unless [`ReplicationConfig::unchanged_toast`]
+/// is set.
+fn with_toast_lookup(replication_config: &ReplicationConfig) -> ReplicationConfig {
+    let mut replication_config = replication_config.clone();
+    replication_config
+        .unchanged_toast
+        .get_or_insert(UnchangedToast::Lookup);
+    replication_config
+}
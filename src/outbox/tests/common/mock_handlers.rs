@@ -1,9 +1,14 @@
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use outbox::{model::EventRecord, EventHandler};
+use outbox::{model::EventRecord, Envelope, EventHandler};
+use rand::Rng;
+use uuid::Uuid;
 
 pub struct FallibleHandler<Inner: EventHandler<EventRecord>> {
     pub succeed_on: usize,
@@ -14,13 +19,31 @@ pub struct FallibleHandler<Inner: EventHandler<EventRecord>> {
 impl<Inner: EventHandler<EventRecord> + Send + Sync> EventHandler<EventRecord>
     for FallibleHandler<Inner>
 {
-    async fn handle(&self, msg: EventRecord) -> anyhow::Result<()> {
+    async fn handle(&self, msg: Envelope<EventRecord>) -> anyhow::Result<()> {
         let prev = self.attempts.fetch_add(1, Ordering::Relaxed);
+        let ttl = msg.change.after().map_or(0, |record| record.ttl);
 
-        if msg.ttl as usize <= self.succeed_on {
+        if ttl as usize <= self.succeed_on {
             self.inner.handle(msg).await
         } else {
             anyhow::bail!("Failed on attempt {}", prev);
         }
     }
 }
+
+/// Records the order of handled events, taking a random amount of time for each.
+pub struct RecordingHandler {
+    pub handled: Arc<Mutex<Vec<Uuid>>>,
+}
+
+impl EventHandler<EventRecord> for RecordingHandler {
+    async fn handle(&self, msg: Envelope<EventRecord>) -> anyhow::Result<()> {
+        let delay = rand::thread_rng().gen_range(0..20);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+
+        if let Some(record) = msg.change.into_after() {
+            self.handled.lock().unwrap().push(record.id);
+        }
+        Ok(())
+    }
+}
@@ -40,30 +40,26 @@ impl TestContext {
                 .collect::<String>()
         )
         .to_lowercase();
-        dbg!(&table);
+        tracing::info!(table, "test context");
         let replication_config = outbox::ReplicationConfig {
             publication: format!("{table}_pub"),
             replication_slot: format!("{table}_slot"),
-            table,
+            tables: vec![table.clone().into()],
+            ..Default::default()
         };
 
         let replication_client = DbClient::<true>::new(&db_config).await.unwrap();
-        setup(&replication_client, &replication_config.table)
-            .await
-            .unwrap();
+        setup(&replication_client, &table).await.unwrap();
 
         reqwest::Client::new()
-            .put(format!(
-                "http://localhost:15672/api/vhosts/{}",
-                replication_config.table
-            ))
+            .put(format!("http://localhost:15672/api/vhosts/{}", table))
             .basic_auth("guest", Some("guest"))
             .send()
             .await
             .unwrap();
 
         let amqp_connection = Connection::connect(
-            &format!("amqp://127.0.0.1:5672/{}", replication_config.table),
+            &format!("amqp://127.0.0.1:5672/{table}"),
             ConnectionProperties::default(),
         )
         .await
@@ -135,6 +131,6 @@ pub async fn consume(mut consumer: lapin::Consumer, n: usize) {
         delivery.ack(BasicAckOptions::default()).await.expect("ack");
 
         let event: TestEvent = serde_json::from_slice(&delivery.data).unwrap();
-        println!("Consumed event: {}", event.event_id);
+        tracing::debug!(event_id = %event.event_id, "consumed event");
     }
 }
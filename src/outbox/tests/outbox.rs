@@ -1,6 +1,9 @@
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use amqp::AmqpPublisher;
@@ -10,8 +13,16 @@ mod common;
 
 use common::{
     consume, insert_some_records, mock_handlers, test_event::TestEvent, TestContext, MOCK_QUEUE,
+    MOCK_ROUTING_KEY,
 };
-use outbox::{client::OutboxClient, handlers, subscriber::OutboxSubscriber};
+use outbox::{
+    client::OutboxClient,
+    handlers::{self, EagerRetryLayer},
+    model::EventRecord,
+    subscriber::{OutboxSubscriber, SupervisedOutboxSubscriber},
+    DbClient, Dispatch, Envelope, HandlerBuilder, Metrics,
+};
+use uuid::Uuid;
 
 #[tokio::test]
 async fn outbox_works() {
@@ -102,3 +113,320 @@ async fn error_gets_retried() {
 
     assert_eq!(total_attempts.load(Ordering::Relaxed), 12);
 }
+
+#[tokio::test]
+async fn retries_are_recorded_in_metrics() {
+    let context = TestContext::new().await;
+
+    let client = OutboxClient::new(&context.db_config, &context.replication_config)
+        .await
+        .unwrap();
+
+    // Fails until TTL is down to 1, so each event is retried twice
+    let metrics = Metrics::new();
+    let handled = Arc::new(Mutex::new(vec![]));
+    let handler = handlers::EagerRetryHandler::new(
+        client.clone(),
+        mock_handlers::FallibleHandler {
+            succeed_on: 1,
+            attempts: Arc::new(AtomicU32::new(0)),
+            inner: mock_handlers::RecordingHandler {
+                handled: handled.clone(),
+            },
+        },
+    )
+    .await
+    .unwrap()
+    .with_metrics(metrics.clone());
+    let sub = OutboxSubscriber::new(&context.db_config, &context.replication_config, handler)
+        .await
+        .unwrap()
+        .with_metrics(metrics.clone());
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    let n = 2;
+    insert_some_records(client, n).await;
+    while handled.lock().unwrap().len() < n * 2 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    assert_eq!(metrics.retries(), 8);
+    assert!(metrics.render().contains(&format!(
+        r#"cdc_retries_total{{slot="{}"}} 8"#,
+        context.replication_config.replication_slot
+    )));
+}
+
+#[tokio::test]
+async fn columns_are_decoded_by_name() {
+    let context = TestContext::new().await;
+
+    // Recreate the table with its columns in a different order
+    let db_client = DbClient::<false>::new(&context.db_config).await.unwrap();
+    db_client
+        .simple_query(&format!(
+            r#"
+            DROP TABLE "{table}";
+            CREATE TABLE "{table}" (
+                created_at TIMESTAMPTZ DEFAULT NOW(),
+                ttl smallint NOT NULL,
+                data BYTEA NOT NULL,
+                event_type TEXT NOT NULL,
+                agg_id UUID NOT NULL,
+                id UUID PRIMARY KEY
+            );
+            "#,
+            table = context.replication_config.tables[0].name,
+        ))
+        .await
+        .unwrap();
+
+    let client = OutboxClient::new(&context.db_config, &context.replication_config)
+        .await
+        .unwrap();
+    let amqp_publisher = AmqpPublisher::<TestEvent>::new(&context.amqp_connection)
+        .await
+        .unwrap();
+
+    let sub = OutboxSubscriber::new(
+        &context.db_config,
+        &context.replication_config,
+        amqp_publisher,
+    )
+    .await
+    .unwrap();
+
+    let _bg = tokio::spawn(async move { sub.listen().await });
+    let mock_consumer = context
+        .amqp_connection
+        .create_channel()
+        .await
+        .unwrap()
+        .basic_consume(
+            MOCK_QUEUE,
+            "mock-consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .unwrap();
+
+    let n = 2;
+    insert_some_records(client, n).await;
+    consume(mock_consumer, n * 2).await;
+}
+
+#[tokio::test]
+async fn supervised_subscriber_reconnects() {
+    let context = TestContext::new().await;
+
+    let client = OutboxClient::new(&context.db_config, &context.replication_config)
+        .await
+        .unwrap();
+    let amqp_publisher = AmqpPublisher::<TestEvent>::new(&context.amqp_connection)
+        .await
+        .unwrap();
+
+    let restarts = Arc::new(AtomicU32::new(0));
+    let sub = SupervisedOutboxSubscriber::new(
+        &context.db_config,
+        &context.replication_config,
+        amqp_publisher,
+    )
+    .await
+    .unwrap()
+    .on_restart({
+        let restarts = restarts.clone();
+        move |_| {
+            restarts.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    let _bg = tokio::spawn(async move { sub.listen().await });
+    let mock_consumer = context
+        .amqp_connection
+        .create_channel()
+        .await
+        .unwrap()
+        .basic_consume(
+            MOCK_QUEUE,
+            "mock-consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .unwrap();
+
+    let n = 2;
+    insert_some_records(client.clone(), n).await;
+    consume(mock_consumer.clone(), n * 2).await;
+
+    // Kill the replication connection
+    let db_client = DbClient::<false>::new(&context.db_config).await.unwrap();
+    db_client
+        .simple_query(&format!(
+            r#"
+            SELECT pg_terminate_backend(active_pid)
+            FROM pg_replication_slots
+            WHERE slot_name = '{slot}';
+            "#,
+            slot = context.replication_config.replication_slot,
+        ))
+        .await
+        .unwrap();
+
+    insert_some_records(client, n).await;
+    consume(mock_consumer, n * 2).await;
+
+    assert!(restarts.load(Ordering::Relaxed) >= 1);
+}
+
+#[tokio::test]
+async fn subscriber_shuts_down_gracefully() {
+    let context = TestContext::new().await;
+
+    let client = OutboxClient::new(&context.db_config, &context.replication_config)
+        .await
+        .unwrap();
+    let amqp_publisher = AmqpPublisher::<TestEvent>::new(&context.amqp_connection)
+        .await
+        .unwrap();
+
+    let sub = OutboxSubscriber::new(
+        &context.db_config,
+        &context.replication_config,
+        amqp_publisher,
+    )
+    .await
+    .unwrap();
+    let shutdown = sub.shutdown_handle();
+
+    let bg = tokio::spawn(async move { sub.listen().await });
+    let mock_consumer = context
+        .amqp_connection
+        .create_channel()
+        .await
+        .unwrap()
+        .basic_consume(
+            MOCK_QUEUE,
+            "mock-consumer",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .unwrap();
+
+    let n = 2;
+    insert_some_records(client, n).await;
+    consume(mock_consumer, n * 2).await;
+
+    shutdown.shutdown();
+    bg.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn partitioned_dispatch_preserves_order_per_aggregate() {
+    let mut context = TestContext::new().await;
+    context.replication_config.dispatch = Dispatch::Partitioned;
+
+    let client = OutboxClient::new(&context.db_config, &context.replication_config)
+        .await
+        .unwrap();
+
+    let handled = Arc::new(Mutex::new(vec![]));
+    let handler = mock_handlers::RecordingHandler {
+        handled: handled.clone(),
+    };
+    let sub = OutboxSubscriber::new(&context.db_config, &context.replication_config, handler)
+        .await
+        .unwrap();
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    let agg_id = Uuid::new_v4();
+    let events = (0..20)
+        .map(|_| TestEvent {
+            event_id: Uuid::new_v4(),
+            agg_id,
+            payload: String::new(),
+        })
+        .collect::<Vec<_>>();
+    let ids = events.iter().map(|e| e.event_id).collect::<Vec<_>>();
+    client.persist(events).await.unwrap();
+
+    while handled.lock().unwrap().len() < ids.len() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(*handled.lock().unwrap(), ids);
+}
+
+#[tokio::test]
+async fn layers_are_composed_into_a_handler() {
+    let context = TestContext::new().await;
+
+    let client = OutboxClient::new(&context.db_config, &context.replication_config)
+        .await
+        .unwrap();
+
+    let seen = Arc::new(AtomicU32::new(0));
+    let attempts = Arc::new(AtomicU32::new(0));
+    let handled = Arc::new(Mutex::new(vec![]));
+    let handler = HandlerBuilder::new()
+        .inspect({
+            let seen = seen.clone();
+            move |_: &Envelope<EventRecord>| {
+                seen.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .filter(|msg: &Envelope<EventRecord>| {
+            msg.change
+                .after()
+                .is_some_and(|record| record.event_type == MOCK_ROUTING_KEY)
+        })
+        .layer(EagerRetryLayer::new(client.clone()))
+        .timeout(Duration::from_secs(5))
+        .handler(mock_handlers::FallibleHandler {
+            succeed_on: 1,
+            attempts: attempts.clone(),
+            inner: mock_handlers::RecordingHandler {
+                handled: handled.clone(),
+            },
+        });
+    let sub = OutboxSubscriber::new(&context.db_config, &context.replication_config, handler)
+        .await
+        .unwrap();
+    let _bg = tokio::spawn(async move { sub.listen().await });
+
+    // Filtered out, so neither retried nor handled
+    let db_client = DbClient::<false>::new(&context.db_config).await.unwrap();
+    db_client
+        .simple_query(&format!(
+            r#"
+            INSERT INTO "{table}" (id, agg_id, event_type, data, ttl)
+            VALUES ('{id}', '{id}', 'ignored', '', 3);
+            "#,
+            table = outbox::outbox_table(&context.replication_config).unwrap(),
+            id = Uuid::new_v4(),
+        ))
+        .await
+        .unwrap();
+    let events = (0..2)
+        .map(|_| TestEvent {
+            event_id: Uuid::new_v4(),
+            agg_id: Uuid::new_v4(),
+            payload: String::new(),
+        })
+        .collect::<Vec<_>>();
+    let mut ids = events.iter().map(|e| e.event_id).collect::<Vec<_>>();
+    client.persist(events).await.unwrap();
+
+    while handled.lock().unwrap().len() < ids.len() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut handled = handled.lock().unwrap().clone();
+    handled.sort();
+    ids.sort();
+    assert_eq!(handled, ids);
+    // Each event is attempted with a TTL of 3, 2 and 1
+    assert_eq!(attempts.load(Ordering::Relaxed), 6);
+    assert_eq!(seen.load(Ordering::Relaxed), 7);
+}